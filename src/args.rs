@@ -1,5 +1,7 @@
 use std::fmt::{self, Display};
+use std::path::PathBuf;
 use clap::{Parser, ValueEnum};
+use crate::utils::TABS;
 
 /// Defines the arguments passed by the user at startup. 
 #[derive(Parser)]
@@ -17,6 +19,9 @@ pub struct Args {
     /// Defines the number of data bits in every character.
     #[arg(value_parser = clap::value_parser!(u8).range(5..=8), long, default_value_t = 8, short)]
     pub databits: u8,
+    /// Defines the number of stop bits in every character.
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=2), long, default_value_t = 1, short)]
+    pub stopbits: u8,
     /// Defines the character that will make nanocom enter command-mode. 
     /// If 'x' is given, then C-x will make nanocom enter command mode.
     #[arg(long, default_value_t = 'a', short)]
@@ -27,18 +32,132 @@ pub struct Args {
     /// serial port parameters can then be adjusted at run-time by commands. 
     #[arg(long, short = 'i')]
     pub noinit: bool,
-    /// If given, nanocom will not *reset* the serial port when exiting. It will just close the filedes 
-    /// and do nothing more. This is useful, for example, for leaving modems connected when exiting nanocom 
-    /// using the "Quit" command (instead of "Exit"), which never resets the serial port. If "--noreset" is 
-    /// given then "Quit" and "Exit" behave essentially the same. 
+    /// If given, nanocom will not *reset* the serial port when exiting. It will leave the settings and 
+    /// the DTR and RTS lines as they are, and close the port without hanging up the line (dropping DTR). 
+    /// This is useful, for example, for leaving modems connected when exiting nanocom using the "Quit" 
+    /// command (instead of "Exit"), which never resets the serial port. If "--noreset" is given then 
+    /// "Quit" and "Exit" behave essentially the same. 
     #[arg(long, short = 'r')]
     pub noreset: bool,
-    /// The name of the serial device to be monitored. 
-    pub port: String
+    /// Defines the file transfer protocol used by the send and receive commands. 
+    #[arg(long, default_value_t = Protocol::Xmodem)]
+    pub protocol: Protocol,
+    /// If given, ZMODEM transfers resume partially transferred files rather than starting them 
+    /// again, when sending this asks the receiver to resume. 
+    #[arg(long)]
+    pub resume: bool,
+    /// Defines the directory files received with a batch protocol are saved in to. 
+    #[arg(long, default_value = ".")]
+    pub receive_dir: PathBuf,
+    /// Sends the given file with the selected protocol as soon as the port is open, then exits 
+    /// without entering the terminal. Can be given more than once with a batch protocol. 
+    #[arg(long, value_name = "FILE", conflicts_with = "receive")]
+    pub send: Vec<String>,
+    /// Receives files with the selected (batch) protocol in to the receive directory as soon as 
+    /// the port is open, then exits without entering the terminal. 
+    #[arg(long)]
+    pub receive: bool,
+    /// Defines the delay in milliseconds after each character sent by the ASCII upload command. 
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub char_delay: u64,
+    /// Defines the delay in milliseconds after each line sent by the ASCII upload command. 
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub line_delay: u64,
+    /// Defines what the line endings of a file are translated to by the ASCII upload command. 
+    #[arg(long, default_value_t = Eol::Raw)]
+    pub eol: Eol,
+    /// If given, the ASCII upload command waits for the device to send something matching this 
+    /// regular expression before sending the next line. 
+    #[arg(long, value_name = "REGEX")]
+    pub prompt: Option<String>,
+    /// If given, everything received from the serial port is written to this file as is. 
+    #[arg(long, value_name = "FILE")]
+    pub logfile: Option<PathBuf>,
+    /// Defines whether the log file is added to or overwritten when opened. 
+    #[arg(long, default_value_t = LogMode::Append)]
+    pub log_mode: LogMode,
+    /// If given, everything sent to the serial port is also written to the log file. 
+    #[arg(long)]
+    pub log_sent: bool,
+    /// Defines what each line received is prefixed with, the time of day, the time since the 
+    /// session started, or the time since the previous line. 
+    #[arg(long, default_value_t = Timestamp::Off)]
+    pub timestamp: Timestamp,
+    /// Defines the strftime format of the time of day used by `--timestamp absolute`. 
+    #[arg(long, value_name = "FORMAT", default_value = "%H:%M:%S%.3f")]
+    pub timestamp_format: String,
+    /// Defines how data received is shown, as text with escape sequences interpreted, as a hex 
+    /// dump of every byte, or raw, passed straight through to the terminal. 
+    #[arg(long, default_value_t = DisplayMode::Text)]
+    pub display: DisplayMode,
+    /// Shorthand for `--display raw`. 
+    #[arg(long, conflicts_with = "display")]
+    pub raw: bool,
+    /// Defines the character set of the text received, UTF-8, or one of the 8 bit sets the bytes 
+    /// from 0x80 up are translated from, code page 437 or ISO 8859-1, -2, -5, -7 or -15. 
+    #[arg(long, default_value_t = Charset::Utf8)]
+    pub charset: Charset,
+    /// Defines how bytes received that aren't valid UTF-8 are shown, as the replacement character, 
+    /// as the hex value of each byte, or as Latin-1 characters. 
+    #[arg(long, value_name = "POLICY", default_value_t = InvalidUtf8::Replace)]
+    pub invalid_utf8: InvalidUtf8,
+    /// Defines how non-ASCII characters typed are encoded before they are sent, as UTF-8, Latin-1 
+    /// or code page 437, or ascii to not send them at all and warn instead. 
+    #[arg(long, value_name = "ENCODING", default_value_t = KeyEncoding::Utf8)]
+    pub key_encoding: KeyEncoding,
+    /// Defines the number of columns between the tab stops the terminal starts with, the device 
+    /// can then set and clear them. 
+    #[arg(value_parser = clap::value_parser!(u16).range(1..), long, value_name = "COLS", default_value_t = TABS)]
+    pub tab_width: u16,
+    /// Defines the mappings applied to data received from the device before it is shown, as a 
+    /// comma separated list. 
+    #[arg(long, value_name = "MAP", value_delimiter = ',')]
+    pub imap: Vec<Map>,
+    /// Defines the mappings applied to keys typed before they are sent to the device, as a comma 
+    /// separated list. 
+    #[arg(long, value_name = "MAP", value_delimiter = ',')]
+    pub omap: Vec<Map>,
+    /// Defines the mappings applied to keys typed before they are shown by local echo, as a comma 
+    /// separated list. 
+    #[arg(long, value_name = "MAP", value_delimiter = ',')]
+    pub emap: Vec<Map>,
+    /// Defines the answerback string sent to the device when it sends ENQ, nothing is sent if it's 
+    /// empty. 
+    #[arg(long, value_name = "STRING", default_value = "")]
+    pub answerback: String,
+    /// If the port isn't there at startup, keep trying to open it until it shows up, for up to the 
+    /// given number of seconds (`--wait=SECS`) or forever if none is given. Exits with code 3 if it 
+    /// times out. 
+    #[arg(long, value_name = "SECS", require_equals = true)]
+    pub wait: Option<Option<u64>>,
+    /// If the port disconnects, keep the terminal running and reopen it with the same settings once 
+    /// it comes back, found by its device name or USB identity. 
+    #[arg(long)]
+    pub reconnect: bool,
+    /// List the serial ports available, with the IDs and descriptions of USB ports, and exit. 
+    #[arg(long)]
+    pub list: bool,
+    /// Print the ports listed by `--list` as JSON. 
+    #[arg(long, requires = "list")]
+    pub json: bool,
+    /// The name of the serial device to be monitored, or a selector picking a USB port: `usb:VID:PID`, 
+    /// `serial:SERIAL` or `desc:TEXT`. Not needed with `--list`. 
+    #[arg(required_unless_present = "list")]
+    pub port: Option<String>
 }
 
 impl Args {
 
+    /// The port given, a device name or selector, only empty when `--list` is given. 
+    pub fn port_name(&self) -> &str {
+        self.port.as_deref().unwrap_or_default()
+    }
+
+    /// The display mode asked for, `--raw` being shorthand for `--display raw`. 
+    pub fn display_mode(&self) -> DisplayMode {
+        if self.raw { DisplayMode::Raw } else { self.display }
+    }
+
     /// Serializes all the startup args to generate an info message at startup. 
     pub fn show_state(&self) -> String {
         format!(
@@ -47,18 +166,51 @@ impl Args {
             baudrate is    : {}\r\n\
             parity is      : {}\r\n\
             databits are   : {}\r\n\
+            stopbits are   : {}\r\n\
             escape is      : C-{}\r\n\
             noinit is      : {}\r\n\
             noreset is     : {}\r\n\
+            reconnect is   : {}\r\n\
+            protocol is    : {}\r\n\
+            receive dir is : {}\r\n\
+            eol is         : {}\r\n\
+            logfile is     : {}\r\n\
+            timestamp is   : {}\r\n\
+            display is     : {}\r\n\
+            tab width is   : {}\r\n\
+            charset is     : {}\r\n\
+            invalid utf8   : {}\r\n\
+            key encoding   : {}\r\n\
+            imap is        : {}\r\n\
+            omap is        : {}\r\n\
+            emap is        : {}\r\n\
             ",
-            &self.port,
+            self.port_name(),
             self.flow.show(),
             self.baud,
             self.parity.show(),
             self.databits,
+            self.stopbits,
             self.escape,
             if self.noinit { "no" } else { "yes" },
-            if self.noreset { "no" } else { "yes" }
+            if self.noreset { "no" } else { "yes" },
+            if self.reconnect { "yes" } else { "no" },
+            self.protocol.show(),
+            self.receive_dir.display(),
+            self.eol.show(),
+            match &self.logfile {
+                Some(v) => format!("{} ({})", v.display(), self.log_mode.show()),
+                None => "none".to_string()
+            },
+            self.timestamp.show(),
+            self.display_mode().show(),
+            self.tab_width,
+            self.charset.show(),
+            self.invalid_utf8.show(),
+            self.key_encoding.show(),
+            Map::show_list(&self.imap),
+            Map::show_list(&self.omap),
+            Map::show_list(&self.emap)
         )
     }
 }
@@ -113,24 +265,36 @@ pub enum Parity {
     E,
     /// no parity mode
     N,
+    /// mark parity mode (Linux only)
+    M,
+    /// space parity mode (Linux only)
+    S,
 }
 
 impl Parity {
-    /// Converts to [serialport::FlowControl] type. 
+    /// Converts to [serialport::Parity] type, mark and space aren't supported by serialport so 
+    /// give [serialport::Parity::None] and are set separately. 
     pub fn to_serialport(&self) -> serialport::Parity {
         match self {
             Parity::O => serialport::Parity::Odd,
             Parity::E => serialport::Parity::Even,
-            Parity::N => serialport::Parity::None
+            Parity::N | Parity::M | Parity::S => serialport::Parity::None
         }
     }
 
+    /// If true the parity is mark or space, which are set with termios rather than serialport. 
+    pub fn is_mark_space(&self) -> bool {
+        matches!(self, Parity::M | Parity::S)
+    }
+
     /// Produces a human readable string of what option is selected. 
     pub fn show(&self) -> &str {
         match self {
             Parity::O => "odd",
             Parity::E => "even",
             Parity::N => "none",
+            Parity::M => "mark",
+            Parity::S => "space",
         }
     }
 }
@@ -141,6 +305,384 @@ impl Display for Parity {
             Parity::O => write!(f, "o"),
             Parity::E => write!(f, "e"),
             Parity::N => write!(f, "n"),
+            Parity::M => write!(f, "m"),
+            Parity::S => write!(f, "s"),
+        }
+    }
+}
+
+/// File transfer protocols available to the send and receive commands. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Protocol {
+    /// XMODEM with 128 byte blocks and an arithmetic checksum
+    Xmodem,
+    /// XMODEM with 128 byte blocks and a CRC-16
+    XmodemCrc,
+    /// XMODEM with 1024 byte blocks and a CRC-16
+    #[value(name = "xmodem-1k")]
+    Xmodem1k,
+    /// YMODEM batch transfer, sending file names, sizes and modification times
+    Ymodem,
+    /// ZMODEM streaming batch transfer with CRC-32 and crash recovery
+    Zmodem,
+    /// Kermit batch transfer with sliding windows, long packets and 8th bit prefixing
+    Kermit,
+}
+
+impl Protocol {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            Protocol::Xmodem => "xmodem",
+            Protocol::XmodemCrc => "xmodem-crc",
+            Protocol::Xmodem1k => "xmodem-1k",
+            Protocol::Ymodem => "ymodem",
+            Protocol::Zmodem => "zmodem",
+            Protocol::Kermit => "kermit",
+        }
+    }
+
+    /// If true the protocol sends file names, so can transfer more than one file at once. 
+    pub fn is_batch(&self) -> bool {
+        matches!(self, Protocol::Ymodem | Protocol::Zmodem | Protocol::Kermit)
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Xmodem => write!(f, "xmodem"),
+            Protocol::XmodemCrc => write!(f, "xmodem-crc"),
+            Protocol::Xmodem1k => write!(f, "xmodem-1k"),
+            Protocol::Ymodem => write!(f, "ymodem"),
+            Protocol::Zmodem => write!(f, "zmodem"),
+            Protocol::Kermit => write!(f, "kermit"),
+        }
+    }
+}
+
+/// Line ending translations done by the ASCII upload command. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Eol {
+    /// send the file as is
+    Raw,
+    /// end each line with a carriage return
+    Cr,
+    /// end each line with a line feed
+    Lf,
+    /// end each line with a carriage return and line feed
+    Crlf,
+}
+
+impl Eol {
+    /// The bytes sent at the end of each line, `None` if the file is sent as is. 
+    pub fn sequence(&self) -> Option<&'static [u8]> {
+        match self {
+            Eol::Raw => None,
+            Eol::Cr => Some(b"\r"),
+            Eol::Lf => Some(b"\n"),
+            Eol::Crlf => Some(b"\r\n"),
+        }
+    }
+
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            Eol::Raw => "raw",
+            Eol::Cr => "cr",
+            Eol::Lf => "lf",
+            Eol::Crlf => "cr/lf",
+        }
+    }
+}
+
+impl Display for Eol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Eol::Raw => write!(f, "raw"),
+            Eol::Cr => write!(f, "cr"),
+            Eol::Lf => write!(f, "lf"),
+            Eol::Crlf => write!(f, "crlf"),
+        }
+    }
+}
+
+/// How the log file is opened. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogMode {
+    /// add to the end of an existing log
+    Append,
+    /// overwrite an existing log
+    Truncate,
+}
+
+impl LogMode {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            LogMode::Append => "append",
+            LogMode::Truncate => "truncate",
+        }
+    }
+}
+
+impl Display for LogMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogMode::Append => write!(f, "append"),
+            LogMode::Truncate => write!(f, "truncate"),
+        }
+    }
+}
+
+/// What each line received is prefixed with. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Timestamp {
+    /// no timestamps
+    Off,
+    /// the time of day
+    Absolute,
+    /// the time since the session started
+    Session,
+    /// the time since the previous line
+    Delta,
+}
+
+impl Timestamp {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            Timestamp::Off => "off",
+            Timestamp::Absolute => "time of day",
+            Timestamp::Session => "since session start",
+            Timestamp::Delta => "since previous line",
+        }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timestamp::Off => write!(f, "off"),
+            Timestamp::Absolute => write!(f, "absolute"),
+            Timestamp::Session => write!(f, "session"),
+            Timestamp::Delta => write!(f, "delta"),
+        }
+    }
+}
+
+/// The character set of the text received. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Charset {
+    /// UTF-8
+    Utf8,
+    /// code page 437, the IBM PC character set with box drawing characters
+    Cp437,
+    /// ISO 8859-1, western European
+    Latin1,
+    /// ISO 8859-2, central European
+    Latin2,
+    /// ISO 8859-5, Cyrillic
+    Cyrillic,
+    /// ISO 8859-7, Greek
+    Greek,
+    /// ISO 8859-15, western European with the euro sign
+    Latin9,
+}
+
+impl Charset {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            Charset::Utf8 => "utf-8",
+            Charset::Cp437 => "cp437",
+            Charset::Latin1 => "iso 8859-1",
+            Charset::Latin2 => "iso 8859-2",
+            Charset::Cyrillic => "iso 8859-5",
+            Charset::Greek => "iso 8859-7",
+            Charset::Latin9 => "iso 8859-15",
+        }
+    }
+}
+
+impl Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Charset::Utf8 => write!(f, "utf8"),
+            Charset::Cp437 => write!(f, "cp437"),
+            Charset::Latin1 => write!(f, "latin1"),
+            Charset::Latin2 => write!(f, "latin2"),
+            Charset::Cyrillic => write!(f, "cyrillic"),
+            Charset::Greek => write!(f, "greek"),
+            Charset::Latin9 => write!(f, "latin9"),
+        }
+    }
+}
+
+/// How bytes received that aren't valid UTF-8 are shown. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum InvalidUtf8 {
+    /// as the replacement character
+    Replace,
+    /// as the hex value of each byte
+    Hex,
+    /// as Latin-1 characters
+    Latin1,
+}
+
+impl InvalidUtf8 {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            InvalidUtf8::Replace => "replacement character",
+            InvalidUtf8::Hex => "hex",
+            InvalidUtf8::Latin1 => "latin-1",
+        }
+    }
+}
+
+impl Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidUtf8::Replace => write!(f, "replace"),
+            InvalidUtf8::Hex => write!(f, "hex"),
+            InvalidUtf8::Latin1 => write!(f, "latin1"),
+        }
+    }
+}
+
+/// How non-ASCII characters typed are encoded before they're sent. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum KeyEncoding {
+    /// as UTF-8
+    Utf8,
+    /// as Latin-1, only characters up to U+00FF can be sent
+    Latin1,
+    /// as code page 437
+    Cp437,
+    /// not sent, only ASCII characters can be typed
+    Ascii,
+}
+
+impl KeyEncoding {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            KeyEncoding::Utf8 => "utf-8",
+            KeyEncoding::Latin1 => "latin-1",
+            KeyEncoding::Cp437 => "cp437",
+            KeyEncoding::Ascii => "ascii",
+        }
+    }
+}
+
+impl Display for KeyEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyEncoding::Utf8 => write!(f, "utf8"),
+            KeyEncoding::Latin1 => write!(f, "latin1"),
+            KeyEncoding::Cp437 => write!(f, "cp437"),
+            KeyEncoding::Ascii => write!(f, "ascii"),
+        }
+    }
+}
+
+/// How data received from the serial port is shown. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum DisplayMode {
+    /// text with escape sequences interpreted
+    Text,
+    /// a hex and ASCII dump of every byte
+    Hex,
+    /// every byte passed straight through to the terminal
+    Raw,
+}
+
+impl DisplayMode {
+    /// Produces a human readable string of what option is selected. 
+    pub fn show(&self) -> &str {
+        match self {
+            DisplayMode::Text => "text",
+            DisplayMode::Hex => "hex dump",
+            DisplayMode::Raw => "raw",
+        }
+    }
+}
+
+impl Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisplayMode::Text => write!(f, "text"),
+            DisplayMode::Hex => write!(f, "hex"),
+            DisplayMode::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+/// Character mappings, applied separately to data in, data out and local echo. 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Map {
+    /// map CR to LF
+    #[value(name = "crlf")]
+    CrLf,
+    /// map CR to CR + LF
+    #[value(name = "crcrlf")]
+    CrCrLf,
+    /// map LF to CR
+    #[value(name = "lfcr")]
+    LfCr,
+    /// map LF to CR + LF
+    #[value(name = "lfcrlf")]
+    LfCrLf,
+    /// ignore CR
+    #[value(name = "igncr")]
+    IgnCr,
+    /// ignore LF
+    #[value(name = "ignlf")]
+    IgnLf,
+    /// map BS to DEL
+    #[value(name = "bsdel")]
+    BsDel,
+    /// map DEL to BS
+    #[value(name = "delbs")]
+    DelBs,
+    /// map special characters (other than CR, LF and TAB) to hex
+    #[value(name = "spchex")]
+    SpcHex,
+    /// map TAB to --tab-width spaces
+    #[value(name = "tabspace")]
+    TabSpace,
+    /// map 8 bit characters to hex
+    #[value(name = "8bithex")]
+    EightBitHex,
+    /// map normal ASCII characters to hex
+    #[value(name = "nrmhex")]
+    NrmHex,
+}
+
+impl Map {
+    /// Produces a human readable string of the mappings in a list. 
+    pub fn show_list(maps: &[Map]) -> String {
+        if maps.is_empty() { return "none".to_string() }
+        maps.iter().map(|m| m.to_string()).collect::<Vec<String>>().join(",")
+    }
+}
+
+impl Display for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Map::CrLf => write!(f, "crlf"),
+            Map::CrCrLf => write!(f, "crcrlf"),
+            Map::LfCr => write!(f, "lfcr"),
+            Map::LfCrLf => write!(f, "lfcrlf"),
+            Map::IgnCr => write!(f, "igncr"),
+            Map::IgnLf => write!(f, "ignlf"),
+            Map::BsDel => write!(f, "bsdel"),
+            Map::DelBs => write!(f, "delbs"),
+            Map::SpcHex => write!(f, "spchex"),
+            Map::TabSpace => write!(f, "tabspace"),
+            Map::EightBitHex => write!(f, "8bithex"),
+            Map::NrmHex => write!(f, "nrmhex"),
         }
     }
 }
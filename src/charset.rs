@@ -0,0 +1,97 @@
+use crate::args::{Charset, KeyEncoding};
+use crate::key::GraphicSet;
+
+
+/// The characters code page 437 has for the bytes 0x80 to 0xFF.
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// The characters ISO 8859-2 has for the bytes 0xA0 to 0xFF.
+const ISO8859_2: [char; 96] = [
+    '\u{a0}', 'Ą', '˘', 'Ł', '¤', 'Ľ', 'Ś', '§', '¨', 'Š', 'Ş', 'Ť', 'Ź', '\u{ad}', 'Ž', 'Ż',
+    '°', 'ą', '˛', 'ł', '´', 'ľ', 'ś', 'ˇ', '¸', 'š', 'ş', 'ť', 'ź', '˝', 'ž', 'ż',
+    'Ŕ', 'Á', 'Â', 'Ă', 'Ä', 'Ĺ', 'Ć', 'Ç', 'Č', 'É', 'Ę', 'Ë', 'Ě', 'Í', 'Î', 'Ď',
+    'Đ', 'Ń', 'Ň', 'Ó', 'Ô', 'Ő', 'Ö', '×', 'Ř', 'Ů', 'Ú', 'Ű', 'Ü', 'Ý', 'Ţ', 'ß',
+    'ŕ', 'á', 'â', 'ă', 'ä', 'ĺ', 'ć', 'ç', 'č', 'é', 'ę', 'ë', 'ě', 'í', 'î', 'ď',
+    'đ', 'ń', 'ň', 'ó', 'ô', 'ő', 'ö', '÷', 'ř', 'ů', 'ú', 'ű', 'ü', 'ý', 'ţ', '˙',
+];
+
+/// The characters ISO 8859-5 has for the bytes 0xA0 to 0xFF.
+const ISO8859_5: [char; 96] = [
+    '\u{a0}', 'Ё', 'Ђ', 'Ѓ', 'Є', 'Ѕ', 'І', 'Ї', 'Ј', 'Љ', 'Њ', 'Ћ', 'Ќ', '\u{ad}', 'Ў', 'Џ',
+    'А', 'Б', 'В', 'Г', 'Д', 'Е', 'Ж', 'З', 'И', 'Й', 'К', 'Л', 'М', 'Н', 'О', 'П',
+    'Р', 'С', 'Т', 'У', 'Ф', 'Х', 'Ц', 'Ч', 'Ш', 'Щ', 'Ъ', 'Ы', 'Ь', 'Э', 'Ю', 'Я',
+    'а', 'б', 'в', 'г', 'д', 'е', 'ж', 'з', 'и', 'й', 'к', 'л', 'м', 'н', 'о', 'п',
+    'р', 'с', 'т', 'у', 'ф', 'х', 'ц', 'ч', 'ш', 'щ', 'ъ', 'ы', 'ь', 'э', 'ю', 'я',
+    '№', 'ё', 'ђ', 'ѓ', 'є', 'ѕ', 'і', 'ї', 'ј', 'љ', 'њ', 'ћ', 'ќ', '§', 'ў', 'џ',
+];
+
+/// The characters ISO 8859-7 has for the bytes 0xA0 to 0xFF, the replacement character where it
+/// has none.
+const ISO8859_7: [char; 96] = [
+    '\u{a0}', '‘', '’', '£', '€', '₯', '¦', '§', '¨', '©', 'ͺ', '«', '¬', '\u{ad}', '\u{fffd}', '―',
+    '°', '±', '²', '³', '΄', '΅', 'Ά', '·', 'Έ', 'Ή', 'Ί', '»', 'Ό', '½', 'Ύ', 'Ώ',
+    'ΐ', 'Α', 'Β', 'Γ', 'Δ', 'Ε', 'Ζ', 'Η', 'Θ', 'Ι', 'Κ', 'Λ', 'Μ', 'Ν', 'Ξ', 'Ο',
+    'Π', 'Ρ', '\u{fffd}', 'Σ', 'Τ', 'Υ', 'Φ', 'Χ', 'Ψ', 'Ω', 'Ϊ', 'Ϋ', 'ά', 'έ', 'ή', 'ί',
+    'ΰ', 'α', 'β', 'γ', 'δ', 'ε', 'ζ', 'η', 'θ', 'ι', 'κ', 'λ', 'μ', 'ν', 'ξ', 'ο',
+    'π', 'ρ', 'ς', 'σ', 'τ', 'υ', 'φ', 'χ', 'ψ', 'ω', 'ϊ', 'ϋ', 'ό', 'ύ', 'ώ', '\u{fffd}',
+];
+
+/// The characters ISO 8859-15 has for the bytes 0xA0 to 0xFF.
+const ISO8859_15: [char; 96] = [
+    '\u{a0}', '¡', '¢', '£', '€', '¥', 'Š', '§', 'š', '©', 'ª', '«', '¬', '\u{ad}', '®', '¯',
+    '°', '±', '²', '³', 'Ž', 'µ', '¶', '·', 'ž', '¹', 'º', '»', 'Œ', 'œ', 'Ÿ', '¿',
+    'À', 'Á', 'Â', 'Ã', 'Ä', 'Å', 'Æ', 'Ç', 'È', 'É', 'Ê', 'Ë', 'Ì', 'Í', 'Î', 'Ï',
+    'Ð', 'Ñ', 'Ò', 'Ó', 'Ô', 'Õ', 'Ö', '×', 'Ø', 'Ù', 'Ú', 'Û', 'Ü', 'Ý', 'Þ', 'ß',
+    'à', 'á', 'â', 'ã', 'ä', 'å', 'æ', 'ç', 'è', 'é', 'ê', 'ë', 'ì', 'í', 'î', 'ï',
+    'ð', 'ñ', 'ò', 'ó', 'ô', 'õ', 'ö', '÷', 'ø', 'ù', 'ú', 'û', 'ü', 'ý', 'þ', 'ÿ',
+];
+
+/// The characters DEC Special Graphics has for `_` to `~`, the rest are the same as ASCII.
+const DEC_SPECIAL_GRAPHICS: [char; 32] = [
+    '\u{a0}', '◆', '▒', '␉', '␌', '␍', '␊', '°', '±', '␤', '␋', '┘', '┐', '┌', '└', '┼',
+    '⎺', '⎻', '─', '⎼', '⎽', '├', '┤', '┴', '┬', '│', '≤', '≥', 'π', '≠', '£', '·',
+];
+
+/// Gives the character a byte from 0x80 to 0xFF stands for in one of the 8 bit character sets,
+/// the bytes from 0x80 to 0x9F are the C1 controls in the ISO 8859 sets.
+pub fn decode(byte: u8, charset: Charset) -> char {
+    let table = match charset {
+        Charset::Cp437 => return CP437[byte as usize - 0x80],
+        Charset::Utf8 | Charset::Latin1 => return byte as char,
+        _ if byte < 0xA0 => return byte as char,
+        Charset::Latin2 => &ISO8859_2,
+        Charset::Cyrillic => &ISO8859_5,
+        Charset::Greek => &ISO8859_7,
+        Charset::Latin9 => &ISO8859_15,
+    };
+    table[byte as usize - 0xA0]
+}
+
+/// Gives the character a printable ASCII character stands for in the graphic set it's shown in.
+pub fn translate(c: char, set: GraphicSet) -> char {
+    match (set, c) {
+        (GraphicSet::Uk, '#') => '£',
+        (GraphicSet::DecSpecialGraphics, '_'..='~') => DEC_SPECIAL_GRAPHICS[c as usize - '_' as usize],
+        _ => c
+    }
+}
+
+/// Encodes a character typed in the given encoding, `None` if the encoding doesn't have it.
+pub fn encode(c: char, encoding: KeyEncoding) -> Option<Vec<u8>> {
+    if c.is_ascii() { return Some(vec![c as u8]) }
+    match encoding {
+        KeyEncoding::Utf8 => Some(c.to_string().into_bytes()),
+        KeyEncoding::Latin1 => u8::try_from(u32::from(c)).ok().map(|b| vec![b]),
+        KeyEncoding::Cp437 => CP437.iter().position(|v| *v == c).map(|i| vec![0x80 + i as u8]),
+        KeyEncoding::Ascii => None,
+    }
+}
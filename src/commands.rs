@@ -1,7 +1,13 @@
-use std::{thread, time::Duration};
+use std::{thread, time::{Duration, Instant}};
 
-use serialport::{DataBits, FlowControl, Parity, StopBits};
+use serialport::{DataBits, FlowControl, StopBits};
 
+use clap::ValueEnum;
+
+use crate::args::{DisplayMode, LogMode, Map, Parity, Timestamp};
+use crate::logfile::LogFile;
+use crate::terminal;
+use crate::transfer;
 use crate::utils::{put_str, BAUDS};
 use super::{State, HandleInputError, InputStream};
 
@@ -18,7 +24,9 @@ pub(crate) fn handle_escape(seq: &Vec<u8>, state: &mut State, input_stream: &Inp
     }
     if state.command_mode {
         state.command_mode = false;
-        let _result = handle_command(key, state, input_stream)?;
+        let result = handle_command(key, state, input_stream);
+        state.remember_settings();
+        result?;
         return Ok(true); 
     }
     if key == state.escape_code {
@@ -54,6 +62,14 @@ fn handle_command(command: u8, state: &mut State, input_stream: &InputStream) ->
         7 => toggle_rts(state),
         3 => toggle_local_echo(state),
         22 => show_port_settings(state),
+        19 => send_file(state, input_stream),
+        18 => receive_file(state, input_stream),
+        23 => upload_ascii(state, input_stream),
+        12 => toggle_log(state, input_stream),
+        15 => rotate_log(state),
+        5 => change_timestamp(state),
+        11 => change_display(state),
+        14 => change_map(state, input_stream),
         8 | 127 => help_message(state),
         _ => Ok(())
     }
@@ -202,21 +218,24 @@ fn change_flowcontrol(state: &mut State) -> Result<(), HandleInputError>  {
 /// Cycles through to the next parity option, showing an error message and 
 /// throwing is failing to set it in the settings. 
 /// 
-/// * [Parity::None] => [Parity::Odd]
-/// * [Parity::Odd] => [Parity::Even]
-/// * [Parity::Even] => [Parity::None]
+/// * [Parity::N] => [Parity::O]
+/// * [Parity::O] => [Parity::E]
+/// * [Parity::E] => [Parity::M] on Linux, otherwise [Parity::N]
+/// * [Parity::M] => [Parity::S]
+/// * [Parity::S] => [Parity::N]
 fn change_parity(state: &mut State) -> Result<(), HandleInputError> {
-    let parity = get_parity(state)?;
-
-    let new_parity = match parity {
-        Parity::None => Parity::Odd,
-        Parity::Odd => Parity::Even,
-        Parity::Even => Parity::None
+    let new_parity = match state.parity {
+        Parity::N => Parity::O,
+        Parity::O => Parity::E,
+        Parity::E if cfg!(target_os = "linux") => Parity::M,
+        Parity::E => Parity::N,
+        Parity::M => Parity::S,
+        Parity::S => Parity::N,
     };
 
-    match state.port.set_parity(new_parity) {
+    match state.set_parity(new_parity) {
         Ok(_) => {
-            println!("\r\n*** parity: {} ***\r\n", new_parity);
+            println!("\r\n*** parity: {} ***\r\n", new_parity.show());
             Ok(())
         },
         Err(_) => {
@@ -292,11 +311,245 @@ fn toggle_local_echo(state: &mut State) -> Result<(), HandleInputError>  {
     Ok(())
 }
 
+/// Cycles through to the next timestamp mode for lines received. 
+/// 
+/// * [Timestamp::Off] => [Timestamp::Absolute]
+/// * [Timestamp::Absolute] => [Timestamp::Session]
+/// * [Timestamp::Session] => [Timestamp::Delta]
+/// * [Timestamp::Delta] => [Timestamp::Off]
+fn change_timestamp(state: &mut State) -> Result<(), HandleInputError> {
+    state.timestamp = match state.timestamp {
+        Timestamp::Off => Timestamp::Absolute,
+        Timestamp::Absolute => Timestamp::Session,
+        Timestamp::Session => Timestamp::Delta,
+        Timestamp::Delta => Timestamp::Off,
+    };
+    state.last_line = Instant::now();
+    state.log_last_line = Instant::now();
+    println!("\r\n*** timestamps: {} ***\r\n", state.timestamp.show());
+    Ok(())
+}
+
+/// Cycles between showing data received as text, as a hex dump and raw. 
+fn change_display(state: &mut State) -> Result<(), HandleInputError> {
+    terminal::reset_hex(state);
+    state.display = match state.display {
+        DisplayMode::Text => DisplayMode::Hex,
+        DisplayMode::Hex => DisplayMode::Raw,
+        DisplayMode::Raw => DisplayMode::Text,
+    };
+    println!("\r\n*** display: {} ***\r\n", state.display.show());
+    Ok(())
+}
+
+/// Polls the user for which of the input, output or echo mappings to change, followed by the new 
+/// comma separated list of mappings, an empty list clears the mappings. 
+fn change_map(state: &mut State, input_stream: &InputStream) -> Result<(), HandleInputError> {
+    println!("\r\n\r\n\
+        *** imap: {}\r\n\
+        *** omap: {}\r\n\
+        *** emap: {}\r",
+        Map::show_list(&state.imap),
+        Map::show_list(&state.omap),
+        Map::show_list(&state.emap)
+    );
+    put_str("*** map to change [imap|omap|emap] followed by the new mappings: ");
+    let line = get_path(input_stream)?;
+    let mut words = line.split_whitespace();
+    let which = match words.next() {
+        Some(v) => v.to_string(),
+        None => {
+            println!("\r\n*** Mappings unchanged \r\n");
+            return Ok(());
+        }
+    };
+    let mut maps = vec![];
+    for name in words.flat_map(|w| w.split(',')).filter(|w| !w.is_empty()) {
+        match Map::from_str(name, true) {
+            Ok(m) => maps.push(m),
+            Err(_) => {
+                println!("\r\n*** Unknown mapping {} \r\n", name);
+                return Err(HandleInputError::Recoverable);
+            }
+        }
+    }
+    let target = match which.as_str() {
+        "imap" => &mut state.imap,
+        "omap" => &mut state.omap,
+        "emap" => &mut state.emap,
+        _ => {
+            println!("\r\n*** Unknown map {}, expected imap, omap or emap \r\n", which);
+            return Err(HandleInputError::Recoverable);
+        }
+    };
+    *target = maps;
+    println!("\r\n*** {}: {} ***\r\n", which, Map::show_list(target));
+    Ok(())
+}
+
+/// Polls the user for the path of a file and sends it using the selected transfer protocol, 
+/// batch protocols take a list of paths separated by spaces. 
+fn send_file(state: &mut State, input_stream: &InputStream) -> Result<(), HandleInputError> {
+    let prompt = if state.protocol.is_batch() { "files" } else { "file" };
+    put_str(&format!("\r\n\r\n*** {} send {}: ", state.protocol.show(), prompt));
+    let line = get_path(input_stream)?;
+    let paths: Vec<String> = if state.protocol.is_batch() {
+        line.split_whitespace().map(|v| v.to_string()).collect()
+    } else {
+        vec![line].into_iter().filter(|v| !v.is_empty()).collect()
+    };
+    if paths.is_empty() {
+        println!("\r\n*** Send cancelled \r\n");
+        return Ok(());
+    }
+    println!("\r\n*** Sending, press [C-{}] to cancel \r\n", state.escape);
+    match transfer::send_files(&paths, state, Some(input_stream)) {
+        Ok(bytes) => {
+            println!("\r\n\r\n*** Sent {} bytes ***\r\n", bytes);
+            Ok(())
+        },
+        Err(e) => {
+            println!("\r\n\r\n*** Failed to send file, {} \r\n", e);
+            Err(HandleInputError::Recoverable)
+        }
+    }
+}
+
+/// Receives a file using the selected transfer protocol, polling the user for the path to save it 
+/// to unless the protocol sends file names. 
+fn receive_file(state: &mut State, input_stream: &InputStream) -> Result<(), HandleInputError> {
+    let path = if state.protocol.is_batch() {
+        None
+    } else {
+        put_str(&format!("\r\n\r\n*** {} receive file: ", state.protocol.show()));
+        let path = get_path(input_stream)?;
+        if path.is_empty() {
+            println!("\r\n*** Receive cancelled \r\n");
+            return Ok(());
+        }
+        Some(path)
+    };
+    println!("\r\n*** Receiving, press [C-{}] to cancel \r\n", state.escape);
+    match transfer::receive_files(path.as_deref(), state, Some(input_stream)) {
+        Ok(bytes) => {
+            println!("\r\n\r\n*** Received {} bytes ***\r\n", bytes);
+            Ok(())
+        },
+        Err(e) => {
+            println!("\r\n\r\n*** Failed to receive file, {} \r\n", e);
+            Err(HandleInputError::Recoverable)
+        }
+    }
+}
+
+/// Polls the user for the path of a text file and sends it as is, paced by the character and 
+/// line delays, and waiting for the prompt if one was given. 
+fn upload_ascii(state: &mut State, input_stream: &InputStream) -> Result<(), HandleInputError> {
+    put_str("\r\n\r\n*** ascii upload file: ");
+    let path = get_path(input_stream)?;
+    if path.is_empty() {
+        println!("\r\n*** Upload cancelled \r\n");
+        return Ok(());
+    }
+    println!("\r\n*** Uploading, press [C-{}] to cancel \r\n", state.escape);
+    match transfer::send_ascii(&path, state, Some(input_stream)) {
+        Ok(bytes) => {
+            println!("\r\n\r\n*** Uploaded {} bytes ***\r\n", bytes);
+            Ok(())
+        },
+        Err(e) => {
+            println!("\r\n\r\n*** Failed to upload file, {} \r\n", e);
+            Err(HandleInputError::Recoverable)
+        }
+    }
+}
+
+/// Starts or stops the session log, when starting the user is polled for the path of the log if 
+/// one wasn't given at startup. 
+/// 
+/// Restarting a log that was stopped always adds to it, the log mode only applies to a new log. 
+fn toggle_log(state: &mut State, input_stream: &InputStream) -> Result<(), HandleInputError> {
+    if let Some(mut log) = state.log.take() {
+        if let Err(e) = log.flush() {
+            println!("\r\n*** Failed to write to log file, {} \r\n", e);
+        }
+        println!("\r\n*** logging to {} stopped ***\r\n", log.path().display());
+        return Ok(());
+    }
+    let (path, mode) = match state.log_path.clone() {
+        Some(v) => (v, LogMode::Append),
+        None => {
+            put_str("\r\n\r\n*** log file: ");
+            let path = get_path(input_stream)?;
+            if path.is_empty() {
+                println!("\r\n*** Logging cancelled \r\n");
+                return Ok(());
+            }
+            (path.into(), state.log_mode)
+        }
+    };
+    match LogFile::open(&path, mode) {
+        Ok(log) => {
+            println!("\r\n*** logging to {} ***\r\n", path.display());
+            state.log = Some(log);
+            state.log_path = Some(path);
+            Ok(())
+        },
+        Err(e) => {
+            println!("\r\n*** Failed to open log file, {} \r\n", e);
+            Err(HandleInputError::Recoverable)
+        }
+    }
+}
+
+/// Moves the running session log aside and starts a new one at the same path. 
+fn rotate_log(state: &mut State) -> Result<(), HandleInputError> {
+    let log = match state.log.as_mut() {
+        Some(v) => v,
+        None => {
+            println!("\r\n*** Logging is not running \r\n");
+            return Err(HandleInputError::Recoverable);
+        }
+    };
+    match log.rotate() {
+        Ok(old) => {
+            println!("\r\n*** log rotated, previous log moved to {} ***\r\n", old.display());
+            Ok(())
+        },
+        Err(e) => {
+            println!("\r\n*** Failed to rotate log file, logging stopped, {} \r\n", e);
+            state.log = None;
+            Err(HandleInputError::Recoverable)
+        }
+    }
+}
+
+/// Receives files with ZMODEM after the remote has started sending, regardless of the selected protocol. 
+pub(crate) fn autostart_zmodem(state: &mut State, input_stream: &InputStream) {
+    state.zmodem_autostart = false;
+    println!("\r\n\r\n*** zmodem send detected, receiving, press [C-{}] to cancel \r\n", state.escape);
+    match transfer::autostart_zmodem(state, input_stream) {
+        Ok(bytes) => println!("\r\n\r\n*** Received {} bytes ***\r\n", bytes),
+        Err(e) => println!("\r\n\r\n*** Failed to receive file, {} \r\n", e),
+    }
+}
+
+/// Reads a line from the user for a file path, throwing a shutdown if the console can't be read. 
+fn get_path(input_stream: &InputStream) -> Result<String, HandleInputError> {
+    match input_stream.get_line() {
+        Ok(s) => Ok(s.trim().to_string()),
+        Err(_) => {
+            println!("\r\n*** Failed to read from console, exiting. \r\n");
+            Err(HandleInputError::Shutdown)
+        }
+    }
+}
+
 /// Prints out a message of all the current serial port settings. 
 fn show_port_settings(state: &mut State) -> Result<(), HandleInputError> {
     let baud = get_baud(state)?;
     let flowcontrol = get_flow_control(state)?;
-    let parity = get_parity(state)?;
+    let parity = state.parity.show().to_string();
     let databits = get_databits(state)?;
     let stopbits = get_stopbits(state)?;
     println!("\r\n\
@@ -336,6 +589,14 @@ fn help_message(state: &mut State) -> Result<(), HandleInputError>  {
         *** [C-t] : Toggle DTR\r\n\
         *** [C-g] : Toggle RTS\r\n\
         *** [C-c] : Toggle local echo\r\n\
+        *** [C-e] : Change timestamp mode\r\n\
+        *** [C-k] : Cycle between text, hex dump and raw display\r\n\
+        *** [C-n] : Change character mappings\r\n\
+        *** [C-s] : Send a file\r\n\
+        *** [C-r] : Receive a file\r\n\
+        *** [C-w] : Upload a text file (paced ASCII)\r\n\
+        *** [C-l] : Start/stop logging to file\r\n\
+        *** [C-o] : Rotate log file\r\n\
         *** [C-v] : Show port settings\r\n\
         *** [C-h] : Show this message\r\n\
     ", state.escape);
@@ -364,17 +625,6 @@ fn get_flow_control(state: &mut State) -> Result<FlowControl, HandleInputError>
     }
 }
 
-/// Gets the current parity setting from the serial port. 
-fn get_parity(state: &mut State) -> Result<Parity, HandleInputError> {
-    match state.port.parity() {
-        Ok(v) => Ok(v),
-        Err(_) => {
-            println!("\r\n*** Failed to read parity \r\n");
-            Err(HandleInputError::Recoverable)
-        }
-    }
-}
-
 /// Gets the current databits setting from the serial port. 
 fn get_databits(state: &mut State) -> Result<DataBits, HandleInputError> {
     match state.port.data_bits() {
@@ -1,22 +1,344 @@
 use std::io;
+use std::sync::atomic::Ordering;
 
-use crate::key::EscapeSequence;
+use console::Style;
+
+use crate::key::{Color, EscapeSequence, Sgr};
 use crate::state::State;
-use crate::utils;
 
 
 /// Handles escape codes received and performs a standard action on the terminal. 
 pub fn handle_escape(parsed: EscapeSequence, state: &mut State) -> Result<(), io::Error> {
     let _ = match parsed {
         EscapeSequence::ArrowDown => state.term.move_cursor_down(1)?,
-        EscapeSequence::ArrowRight => state.term.move_cursor_right(1)?,
-        EscapeSequence::ArrowLeft => state.term.move_cursor_left(1)?,
+        EscapeSequence::ArrowRight => {
+            forward(1, state);
+            state.term.move_cursor_right(1)?
+        },
+        EscapeSequence::ArrowLeft => {
+            state.column = state.column.saturating_sub(1);
+            state.term.move_cursor_left(1)?
+        },
         EscapeSequence::ArrowUp => state.term.move_cursor_up(1)?,
-        EscapeSequence::Home => state.term.clear_screen()?,
-        EscapeSequence::BackTab => state.term.move_cursor_left(utils::TABS)?,
+        EscapeSequence::BackTab => back_tab(1, state)?,
+        EscapeSequence::Sgr(attrs) => {
+            attrs.iter().for_each(|a| state.text_style.apply(a));
+            state.style = state.text_style.to_style();
+        },
+        EscapeSequence::CursorPosition(row, col) => {
+            state.column = col - 1;
+            state.term.move_cursor_to(col as usize - 1, row as usize - 1)?
+        },
+        EscapeSequence::CursorUp(n) => state.term.move_cursor_up(n as usize)?,
+        EscapeSequence::CursorDown(n) => state.term.move_cursor_down(n as usize)?,
+        EscapeSequence::CursorForward(n) => {
+            forward(n, state);
+            state.term.move_cursor_right(n as usize)?
+        },
+        EscapeSequence::CursorBack(n) => {
+            state.column = state.column.saturating_sub(n);
+            state.term.move_cursor_left(n as usize)?
+        },
+        // Term's own clears go back to the start of the line first, so these are written as is. 
+        EscapeSequence::EraseDisplay(n @ 0..=2) => state.term.write_str(&format!("\x1b[{}J", n))?,
+        EscapeSequence::EraseLine(n @ 0..=2) => state.term.write_str(&format!("\x1b[{}K", n))?,
+        EscapeSequence::SaveCursor => {
+            state.saved_style = state.text_style.clone();
+            state.saved_column = state.column;
+            state.term.write_str("\x1b7")?
+        },
+        EscapeSequence::RestoreCursor => {
+            state.text_style = state.saved_style.clone();
+            state.column = state.saved_column;
+            state.style = state.text_style.to_style();
+            state.term.write_str("\x1b8")?
+        },
+        EscapeSequence::ScrollRegion(top, bottom) => {
+            state.scroll_region = top > 1 || bottom > 0;
+            let show = |v: u16| if v == 0 { String::new() } else { v.to_string() };
+            state.term.write_str(&format!("\x1b[{};{}r", show(top), show(bottom)))?
+        },
+        EscapeSequence::ReverseIndex => state.term.write_str("\x1bM")?,
+        EscapeSequence::CursorBackTab(n) => back_tab(n, state)?,
+        EscapeSequence::SetTabStop => state.tab_stops.set(state.column),
+        EscapeSequence::ClearTabStop(0) => state.tab_stops.clear(state.column),
+        EscapeSequence::ClearTabStop(3) => state.tab_stops.clear_all(),
+        EscapeSequence::DesignateCharset(slot, set) => state.graphic_sets[slot] = set,
+        // A VT100 with advanced video. 
+        EscapeSequence::DeviceAttributes => state.reply(b"\x1b[?1;2c"),
+        EscapeSequence::DeviceStatus => state.reply(b"\x1b[0n"),
+        EscapeSequence::ReportCursorPosition => report_cursor_position(state)?,
         _ => (),
     };
 
     Ok(())
 }
 
+
+/// Asks the terminal nanocom is running in where the cursor is, its answer is picked out of the 
+/// keys by the input stream and passed on to the device by [reply_cursor_position]. If the output 
+/// isn't a terminal that can answer, the top left is given straight away. 
+fn report_cursor_position(state: &mut State) -> Result<(), io::Error> {
+    if !state.term.is_term() || cfg!(target_os = "windows") {
+        state.reply(b"\x1b[1;1R");
+        return Ok(());
+    }
+    state.cursor_reports.fetch_add(1, Ordering::SeqCst);
+    state.term.write_str("\x1b[6n")
+}
+
+/// Passes the cursor position given by the terminal on to the device. 
+pub fn reply_cursor_position(row: u16, col: u16, state: &mut State) {
+    state.reply(format!("\x1b[{};{}R", row, col).as_bytes());
+}
+
+/// Puts back anything the device changed about the terminal that would outlast nanocom, the 
+/// scroll region, so the terminal isn't left that way on exit. 
+pub fn reset_terminal(state: &mut State) {
+    if state.scroll_region {
+        let _ = state.term.write_str("\x1b[r");
+        state.scroll_region = false;
+    }
+}
+
+/// Moves the cursor right to the next tab stop, or to the last column if there are none left. 
+pub fn tab(state: &mut State) -> Result<(), io::Error> {
+    let last = state.term.size().1.saturating_sub(1);
+    let column = state.column.min(last);
+    let next = state.tab_stops.next(column, last);
+    state.column = next;
+    state.term.move_cursor_right((next - column) as usize)
+}
+
+/// Moves the cursor left `n` tab stops, stopping at the first column. 
+fn back_tab(n: u16, state: &mut State) -> Result<(), io::Error> {
+    let column = state.column.min(state.term.size().1.saturating_sub(1));
+    let mut prev = column;
+    for _ in 0..n { prev = state.tab_stops.prev(prev) }
+    state.column = prev;
+    state.term.move_cursor_left((column - prev) as usize)
+}
+
+/// Moves the column the cursor is followed at `n` columns right, no further than the last one. 
+fn forward(n: u16, state: &mut State) {
+    let last = state.term.size().1.saturating_sub(1);
+    state.column = state.column.saturating_add(n).min(last);
+}
+
+/// The columns tabs stop at, every `--tab-width` columns to start with, which the device can then 
+/// set and clear. Columns past those the device has changed keep the stops they started with, so 
+/// the stops still reach the edge after the terminal is made wider. 
+pub struct TabStops {
+    /// Whether there's a stop at each column, from the first up to the last one changed. 
+    stops: Vec<bool>,
+    /// The number of columns between the stops past the end of `stops`, 0 once they're all cleared. 
+    width: u16,
+}
+
+impl TabStops {
+
+    /// Creates the tab stops with one every `width` columns. 
+    pub fn new(width: u16) -> TabStops {
+        TabStops { stops: Vec::new(), width }
+    }
+
+    /// Checks if there's a tab stop at the 0 based column. 
+    fn is_stop(&self, column: u16) -> bool {
+        match self.stops.get(column as usize) {
+            Some(v) => *v,
+            None => self.width > 0 && column.is_multiple_of(self.width)
+        }
+    }
+
+    /// Sets or clears the tab stop at the column, noting the stops up to it so they stay put. 
+    fn put(&mut self, column: u16, stop: bool) {
+        while self.stops.len() <= column as usize {
+            let v = self.is_stop(self.stops.len() as u16);
+            self.stops.push(v);
+        }
+        self.stops[column as usize] = stop;
+    }
+
+    /// Sets a tab stop at the column. 
+    pub fn set(&mut self, column: u16) {
+        self.put(column, true);
+    }
+
+    /// Clears the tab stop at the column. 
+    pub fn clear(&mut self, column: u16) {
+        self.put(column, false);
+    }
+
+    /// Clears every tab stop. 
+    pub fn clear_all(&mut self) {
+        self.stops.clear();
+        self.width = 0;
+    }
+
+    /// Gives the column of the next tab stop after `column`, or `last` if there's none before it. 
+    pub fn next(&self, column: u16, last: u16) -> u16 {
+        (column + 1..last).find(|&v| self.is_stop(v)).unwrap_or(last.max(column))
+    }
+
+    /// Gives the column of the tab stop before `column`, or the first column if there's none. 
+    pub fn prev(&self, column: u16) -> u16 {
+        (1..column).rev().find(|&v| self.is_stop(v)).unwrap_or(0)
+    }
+}
+
+/// The colors and attributes set by SGR sequences received, applied to the text printed after them. 
+#[derive(Clone, Default)]
+pub struct TextStyle {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    blink: bool,
+    reverse: bool,
+    hidden: bool,
+    strikethrough: bool,
+}
+
+impl TextStyle {
+
+    /// Makes the change to the colors or attributes given. 
+    pub fn apply(&mut self, attr: &Sgr) {
+        match *attr {
+            Sgr::Reset => *self = TextStyle::default(),
+            Sgr::Bold => self.bold = true,
+            Sgr::Dim => self.dim = true,
+            Sgr::Italic => self.italic = true,
+            Sgr::Underline => self.underline = true,
+            Sgr::Blink => self.blink = true,
+            Sgr::Reverse => self.reverse = true,
+            Sgr::Hidden => self.hidden = true,
+            Sgr::Strikethrough => self.strikethrough = true,
+            Sgr::NormalIntensity => (self.bold, self.dim) = (false, false),
+            Sgr::NoItalic => self.italic = false,
+            Sgr::NoUnderline => self.underline = false,
+            Sgr::NoBlink => self.blink = false,
+            Sgr::NoReverse => self.reverse = false,
+            Sgr::NoHidden => self.hidden = false,
+            Sgr::NoStrikethrough => self.strikethrough = false,
+            Sgr::Foreground(c) => self.fg = Some(c),
+            Sgr::Background(c) => self.bg = Some(c),
+            Sgr::DefaultForeground => self.fg = None,
+            Sgr::DefaultBackground => self.bg = None,
+        }
+    }
+
+    /// Gives the [Style] to print text with, 24 bit colors are shown as the closest of the 256 
+    /// colors as that's all [Style] supports. 
+    pub fn to_style(&self) -> Style {
+        let mut style = Style::new();
+        if let Some(c) = self.fg {
+            let (color, bright) = term_color(c);
+            style = style.fg(color);
+            if bright { style = style.bright() }
+        }
+        if let Some(c) = self.bg {
+            let (color, bright) = term_color(c);
+            style = style.bg(color);
+            if bright { style = style.on_bright() }
+        }
+        if self.bold { style = style.bold() }
+        if self.dim { style = style.dim() }
+        if self.italic { style = style.italic() }
+        if self.underline { style = style.underlined() }
+        if self.blink { style = style.blink() }
+        if self.reverse { style = style.reverse() }
+        if self.hidden { style = style.hidden() }
+        if self.strikethrough { style = style.strikethrough() }
+        style
+    }
+}
+
+/// Gives the [console::Color] for a color, and whether it's one of the bright standard colors. 
+fn term_color(color: Color) -> (console::Color, bool) {
+    const STANDARD: [console::Color; 8] = [
+        console::Color::Black,
+        console::Color::Red,
+        console::Color::Green,
+        console::Color::Yellow,
+        console::Color::Blue,
+        console::Color::Magenta,
+        console::Color::Cyan,
+        console::Color::White,
+    ];
+    match color {
+        Color::Indexed(v @ 0..=7) => (STANDARD[v as usize], false),
+        Color::Indexed(v @ 8..=15) => (STANDARD[v as usize - 8], true),
+        Color::Indexed(v) => (console::Color::Color256(v), false),
+        Color::Rgb(r, g, b) => (console::Color::Color256(nearest_256(r, g, b)), false),
+    }
+}
+
+/// Finds the closest of the 256 colors to a 24 bit color, from the 6x6x6 color cube or the 
+/// grayscale ramp. 
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [i32; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |v: u8| (0..6).min_by_key(|&i| (LEVELS[i] - v as i32).abs()).unwrap_or(0);
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let distance = |cr: i32, cg: i32, cb: i32| 
+        (cr - r as i32).pow(2) + (cg - g as i32).pow(2) + (cb - b as i32).pow(2);
+
+    let cube = 16 + 36 * ri + 6 * gi + bi;
+    let cube_distance = distance(LEVELS[ri], LEVELS[gi], LEVELS[bi]);
+    let average = (r as i32 + g as i32 + b as i32) / 3;
+    let gray = ((average - 8).max(0) / 10).min(23);
+    let gray_level = 8 + gray * 10;
+    if distance(gray_level, gray_level, gray_level) < cube_distance {
+        (232 + gray) as u8
+    } else {
+        cube as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_stops() {
+        let stops = TabStops::new(8);
+        assert_eq!(stops.next(0, 79), 8);
+        assert_eq!(stops.next(3, 79), 8);
+        assert_eq!(stops.next(8, 79), 16);
+        assert_eq!(stops.next(75, 79), 79);
+        assert_eq!(stops.next(79, 79), 79);
+        assert_eq!(stops.prev(12), 8);
+        assert_eq!(stops.prev(8), 0);
+        assert_eq!(stops.prev(0), 0);
+        assert_eq!(TabStops::new(4).next(5, 79), 8);
+    }
+
+    /// The stops carry on past the columns the device changed, so a wider terminal still has them. 
+    #[test]
+    fn stops_reach_wider_terminals() {
+        let mut stops = TabStops::new(8);
+        stops.set(4);
+        assert_eq!(stops.next(0, 79), 4);
+        assert_eq!(stops.next(4, 79), 8);
+        assert_eq!(stops.next(150, 199), 152);
+        assert_eq!(stops.prev(199), 192);
+    }
+
+    #[test]
+    fn clearing_stops() {
+        let mut stops = TabStops::new(8);
+        stops.clear(16);
+        assert_eq!(stops.next(8, 79), 24);
+        assert_eq!(stops.prev(24), 8);
+        stops.clear_all();
+        assert_eq!(stops.next(0, 79), 79);
+        assert_eq!(stops.prev(40), 0);
+        stops.set(10);
+        stops.set(30);
+        assert_eq!(stops.next(0, 199), 10);
+        assert_eq!(stops.next(10, 199), 30);
+        assert_eq!(stops.next(30, 199), 199);
+        assert_eq!(stops.prev(50), 30);
+    }
+}
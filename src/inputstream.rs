@@ -1,10 +1,12 @@
 use std::thread::{self, JoinHandle};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 
 use super::utils;
-use super::key::KeyIn;
+use super::key::{EscapeSequence, KeyIn};
 
-use console::Term;
+use console::{Key, Term};
 
 /// Struct to hold the handle for the user key in polling routine and the receiver from that
 /// routine. 
@@ -22,7 +24,10 @@ impl InputStream {
     /// 
     /// * `escape` - The escape to enter command mode. 
     /// * `shutdown_vals` - A list of bytes that would trigger a shutdown. 
-    pub fn new(escape: u8, shutdown_vals: Vec<u8>) -> InputStream {
+    /// * `waker` - Sent to every time a key is received, so the main loop can wait on it. 
+    /// * `reports` - How many cursor position reports have been asked of the terminal, which are 
+    ///   picked out of the keys rather than passed on as typed. 
+    pub fn new(escape: u8, shutdown_vals: Vec<u8>, waker: Sender<()>, reports: Arc<AtomicUsize>) -> InputStream {
         let (char_sender, char_recv) = mpsc::channel::<KeyIn>();
         let (is_connected_sender, is_connected_rev) = mpsc::channel::<()>();
         let shutdown_chars: Vec<char> = shutdown_vals.iter()
@@ -30,7 +35,7 @@ impl InputStream {
             .collect();
 
         let handle = thread::spawn(move || 
-            input_stream_loop(escape, shutdown_chars, char_sender, is_connected_rev));
+            input_stream_loop(escape, shutdown_chars, char_sender, waker, reports, is_connected_rev));
 
         InputStream { 
             _handle: handle, 
@@ -72,6 +77,10 @@ fn get_char_blocking(result: &mut Vec<char>, char_recv: &Receiver<KeyIn>) -> Res
             result.push(x as char);
             utils::put_char(x as char);
         },
+        Ok(KeyIn::Unicode(x)) => {
+            result.push(x);
+            utils::put_char(x);
+        },
         Err(_) => return Err(()),
         _ => return Ok(false)
     };
@@ -81,7 +90,15 @@ fn get_char_blocking(result: &mut Vec<char>, char_recv: &Receiver<KeyIn>) -> Res
 /// Main loop which sends any keys received from the user input to a channel. 
 /// * `escape` - The escape to enter command mode. 
 /// * `shutdown_vals` - A list of bytes that would trigger a shutdown (so it knows to exit). 
-fn input_stream_loop(escape: u8, shutdown_chars: Vec<char>, char_sender: Sender<KeyIn>, is_connected_rev: Receiver<()>) {
+/// * `waker` - Sent to after every key, waking the main loop. 
+/// * `reports` - How many cursor position reports are expected from the terminal. 
+fn input_stream_loop(escape: u8, 
+    shutdown_chars: Vec<char>, 
+    char_sender: Sender<KeyIn>, 
+    waker: Sender<()>, 
+    reports: Arc<AtomicUsize>,
+    is_connected_rev: Receiver<()>) 
+{
     let term = Term::stdout();
     let mut is_escaped = false;
     loop {
@@ -90,13 +107,55 @@ fn input_stream_loop(escape: u8, shutdown_chars: Vec<char>, char_sender: Sender<
             Ok(c) => c,
             Err(_) => return
         };
-        let key = KeyIn::from_console_key(&c);
-        if let Err(_) = char_sender.send(key.clone()) { return };
-        if let KeyIn::Char(c) = key {
-            if shutdown_chars.contains(&(c as char)) && is_escaped { return }
-            is_escaped = !is_escaped && c == escape;
-        } else {
-            is_escaped = false;
+        let keys = match &c {
+            Key::UnknownEscSeq(seq) if seq.first() == Some(&'[') && reports.load(Ordering::SeqCst) > 0 => 
+                read_cursor_report(&term, seq, &reports),
+            c => vec![KeyIn::from_console_key(c)]
+        };
+        for key in keys {
+            if let Err(_) = char_sender.send(key.clone()) { return };
+            let _ = waker.send(());
+            if let KeyIn::Char(c) = key {
+                if shutdown_chars.contains(&(c as char)) && is_escaped { return }
+                is_escaped = !is_escaped && c == escape;
+            } else {
+                is_escaped = false;
+            };
+        }
+    }
+}
+
+/// Reads the rest of a cursor position report from the terminal, `ESC[row;colR`, which console 
+/// splits in to an unknown sequence of the first few characters followed by the rest as keys. 
+/// Gives back the keys as they were if it turns out not to be a report. 
+fn read_cursor_report(term: &Term, start: &[char], reports: &AtomicUsize) -> Vec<KeyIn> {
+    let mut text: String = start[1..].iter().collect();
+    let mut keys = vec![KeyIn::from_console_key(&Key::UnknownEscSeq(start.to_vec()))];
+    while text.len() < 12 && text.chars().all(|c| c.is_ascii_digit() || c == ';') {
+        let c = match term.read_key_raw() {
+            Ok(c) => c,
+            Err(_) => break
         };
+        match c {
+            Key::Char('R') => {
+                let pos = text.split_once(';')
+                    .and_then(|(row, col)| Some((row.parse::<u16>().ok()?, col.parse::<u16>().ok()?)));
+                if let Some((row, col)) = pos {
+                    reports.fetch_sub(1, Ordering::SeqCst);
+                    return vec![KeyIn::Escape(EscapeSequence::CursorReport(row, col))];
+                }
+                keys.push(KeyIn::from_console_key(&c));
+                break;
+            },
+            Key::Char(v) if v.is_ascii_digit() || v == ';' => {
+                text.push(v);
+                keys.push(KeyIn::from_console_key(&c));
+            },
+            c => {
+                keys.push(KeyIn::from_console_key(&c));
+                break;
+            }
+        }
     }
+    keys
 }
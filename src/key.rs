@@ -1,12 +1,12 @@
 use console::Key;
 
-use crate::utils;
-
 
 /// Data from reading the serial port
 #[derive(Debug, Clone)]
 pub enum KeyIn {
     Char(u8),
+    /// A character typed that isn't ASCII. 
+    Unicode(char),
     Nothing,
     Escape(EscapeSequence)
 }
@@ -18,7 +18,8 @@ impl KeyIn {
     #[cfg(target_os = "windows")]
     pub fn from_console_key(c: &Key) -> Self {
         match c {
-            Key::Char(c) => KeyIn::Char(utils::get_ascii_byte(*c)),
+            Key::Char(c) if c.is_ascii() => KeyIn::Char(*c as u8),
+            Key::Char(c) => KeyIn::Unicode(*c),
             Key::CtrlC => KeyIn::Char(3),
             Key::Tab => KeyIn::Char(9),
             Key::Enter => KeyIn::Char(13),
@@ -32,7 +33,8 @@ impl KeyIn {
     #[cfg(not(target_os = "windows"))]
     pub fn from_console_key(c: &Key) -> Self {
         match c {
-            Key::Char(c) => KeyIn::Char(utils::get_ascii_byte(*c)),
+            Key::Char(c) if c.is_ascii() => KeyIn::Char(*c as u8),
+            Key::Char(c) => KeyIn::Unicode(*c),
             Key::Home => KeyIn::Char(1),
             Key::CtrlC => KeyIn::Char(3),
             Key::End => KeyIn::Char(5),
@@ -45,11 +47,12 @@ impl KeyIn {
     }
 
     /// Returns a vector of bytes containing either the byte of the single char 
-    /// if [KeyIn::Char] or an escape code sequence if [KeyIn::Escape], or an empty 
-    /// vec if [KeyIn::Nothing]. 
+    /// if [KeyIn::Char], the UTF-8 encoding of the character if [KeyIn::Unicode], 
+    /// or an escape code sequence if [KeyIn::Escape], or an empty vec if [KeyIn::Nothing]. 
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             Self::Char(c) => vec![*c],
+            Self::Unicode(c) => c.to_string().into_bytes(),
             Self::Nothing => vec![],
             Self::Escape(e) => e.to_bytes(),
         }
@@ -74,6 +77,96 @@ pub enum EscapeSequence {
     Insert,
     PageUp,
     PageDown,
+    /// Select Graphic Rendition, changing the colors and attributes of the text that follows. 
+    Sgr(Vec<Sgr>),
+    /// Cursor Position, moving the cursor to a 1 based row and column. 
+    CursorPosition(u16, u16),
+    /// Moves the cursor up a number of rows. 
+    CursorUp(u16),
+    /// Moves the cursor down a number of rows. 
+    CursorDown(u16),
+    /// Moves the cursor right a number of columns. 
+    CursorForward(u16),
+    /// Moves the cursor left a number of columns. 
+    CursorBack(u16),
+    /// Erase in Display, 0 erases from the cursor to the end of the screen, 1 from the start of the 
+    /// screen to the cursor and 2 the whole screen. 
+    EraseDisplay(u16),
+    /// Erase in Line, 0 erases from the cursor to the end of the line, 1 from the start of the line 
+    /// to the cursor and 2 the whole line. 
+    EraseLine(u16),
+    /// DECSC, saving the cursor position and text attributes. 
+    SaveCursor,
+    /// DECRC, restoring the cursor position and text attributes saved. 
+    RestoreCursor,
+    /// DECSTBM, setting the top and bottom rows the screen scrolls between, 0 for the defaults. 
+    ScrollRegion(u16, u16),
+    /// Reverse Index, moving the cursor up a row, scrolling down if it's at the top of the scroll region. 
+    ReverseIndex,
+    /// Primary Device Attributes, asking what kind of terminal this is. 
+    DeviceAttributes,
+    /// Device Status Report, asking if the terminal is working. 
+    DeviceStatus,
+    /// Device Status Report, asking where the cursor is. 
+    ReportCursorPosition,
+    /// Cursor Backward Tabulation, moving the cursor back a number of tab stops. 
+    CursorBackTab(u16),
+    /// Horizontal Tab Set, setting a tab stop at the cursor. 
+    SetTabStop,
+    /// Tab Clear, 0 clears the tab stop at the cursor and 3 clears them all. 
+    ClearTabStop(u16),
+    /// Select Character Set, designating the graphic set used as G0 (0) or G1 (1). 
+    DesignateCharset(usize, GraphicSet),
+    /// Cursor Position Report, the 1 based row and column of the cursor given by the terminal 
+    /// nanocom is running in. 
+    CursorReport(u16, u16),
+}
+
+/// A graphic set that can be designated as G0 or G1, changing how the printable ASCII characters 
+/// are shown. 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GraphicSet {
+    #[default]
+    Ascii,
+    /// ASCII with `#` shown as `£`. 
+    Uk,
+    /// The line drawing characters, shown in place of `_` to `~`. 
+    DecSpecialGraphics,
+}
+
+/// A color set by a Select Graphic Rendition sequence. 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// One of the 256 colors, 0-7 are the standard colors, 8-15 their bright versions. 
+    Indexed(u8),
+    /// A 24 bit RGB color. 
+    Rgb(u8, u8, u8),
+}
+
+/// An attribute change made by a Select Graphic Rendition sequence. 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sgr {
+    Reset,
+    Bold,
+    Dim,
+    Italic,
+    Underline,
+    Blink,
+    Reverse,
+    Hidden,
+    Strikethrough,
+    /// Neither bold nor dim. 
+    NormalIntensity,
+    NoItalic,
+    NoUnderline,
+    NoBlink,
+    NoReverse,
+    NoHidden,
+    NoStrikethrough,
+    Foreground(Color),
+    Background(Color),
+    DefaultForeground,
+    DefaultBackground,
 }
 
 impl EscapeSequence {
@@ -82,7 +175,7 @@ impl EscapeSequence {
     pub fn from_console_key(c: Key) -> Self {
         match c {
             Key::UnknownEscSeq(s) => 
-                EscapeSequence::UnknownSeq(s.iter().map(|e| utils::get_ascii_byte(*e)).collect()),
+                EscapeSequence::UnknownSeq(s.iter().flat_map(|e| e.to_string().into_bytes()).collect()),
             Key::ArrowLeft => EscapeSequence::ArrowLeft,
             Key::ArrowRight => EscapeSequence::ArrowRight,
             Key::ArrowUp => EscapeSequence::ArrowUp,
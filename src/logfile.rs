@@ -0,0 +1,64 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::args::LogMode;
+
+
+/// An open session log, raw bytes sent and received over the serial port are written to it as is.
+/// Writes are buffered, the log is flushed after each burst of data and when it's rotated or
+/// stopped.
+pub struct LogFile {
+    /// The path the log was opened at.
+    path: PathBuf,
+    /// The open log file.
+    file: BufWriter<File>,
+}
+
+impl LogFile {
+
+    /// Opens the log file at the given path, creating it if it doesn't exist.
+    ///
+    /// * `mode` - Whether to add to the end of an existing file or overwrite it.
+    pub fn open(path: &Path, mode: LogMode) -> io::Result<LogFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(mode == LogMode::Append)
+            .truncate(mode == LogMode::Truncate)
+            .open(path)?;
+        Ok(LogFile { path: path.to_path_buf(), file: BufWriter::new(file) })
+    }
+
+    /// The path the log is being written to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes bytes to the end of the log.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    /// Writes anything buffered out to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Moves the current log aside, to the first free of `<path>.1`, `<path>.2`, etc, and starts
+    /// a new empty log at the same path, returning where the old log was moved to.
+    pub fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.file.flush()?;
+        let mut num = 1;
+        let rotated = loop {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", num));
+            let candidate = PathBuf::from(name);
+            if !candidate.exists() { break candidate }
+            num += 1;
+        };
+        fs::rename(&self.path, &rotated)?;
+        self.file = BufWriter::new(File::create(&self.path)?);
+        Ok(rotated)
+    }
+}
@@ -22,14 +22,20 @@
 //! run picocom and I'd suggest using that. 
 //! 
 //! There will also be some minor differences between how escape sequences and control characters 
-//! are handled, and file send/receive is not yet implemented (still deciding how best to do that). 
+//! are handled, and file send/receive is built in rather than invoking external programs like `sz`/`rz`. 
 //! 
 //! ## Usage
 //! 
 //! ```$ nanocom [OPTIONS] <PORT>```
 //! 
+//! ```$ nanocom --list [--json]```
+//! 
 //! #### Arguments:
-//!   `<PORT>`  The name of the serial device to be monitored
+//!   `<PORT>`  The name of the serial device to be monitored, or a selector picking a USB port, not needed with `--list`. 
+//!             Selectors are `usb:VID:PID` (or just `usb:VID`) with the IDs in hex, `serial:SERIAL` matching the serial 
+//!             number, and `desc:TEXT` matching part of the manufacturer or product description, for example 
+//!             `usb:0403:6001`, `serial:FT12AB34` or `desc:CP2102`. If more than one port matches, the candidates 
+//!             are listed and nanocom exits with code 1, as it does for an invalid selector
 //! 
 //! #### Options:
 //! 
@@ -38,10 +44,12 @@
 //! *  `-f, --flow <FLOW> `         Defines the flow-control mode to set the serial-port to [default: n] 
 //!                                 [possible values: x, h, n]
 //! *  `-p, --parity <PARITY>`      Defines the flow-control mode to set the serial-port to [default: n] 
-//!                                 [possible values: o, e, n]
+//!                                 [possible values: o, e, n, m, s], mark (m) and space (s) are only supported on Linux
 //! 
 //! *  `-d, --databits <DATABITS>`  Defines the number of data bits in every character [default: 8]
 //! 
+//! *  `-s, --stopbits <STOPBITS>`  Defines the number of stop bits in every character [default: 1]
+//! 
 //! *  `-e, --escape <ESCAPE>`      Defines the character that will make nanocom enter command-mode. 
 //!                                 If 'x' is given, then C-x will make nanocom enter command mode [default: a]
 //! 
@@ -53,11 +61,108 @@
 //!                                 be adjusted at run-time by commands
 //! 
 //! *  `-r, --noreset`              If given, nanocom will not *reset* the serial port when exiting. 
-//!                                 It will just close the filedes and do nothing more. This is useful, 
+//!                                 It will leave the settings and the DTR and RTS lines as they are, and close 
+//!                                 the port without hanging up the line (dropping DTR). This is useful, 
 //!                                 for example, for leaving modems connected when exiting nanocom using the 
 //!                                 "Quit" command (instead of "Exit"), which never resets the serial port. 
 //!                                 If "--noreset" is given then "Quit" and "Exit" behave essentially the same
 //! 
+//! *  `--protocol <PROTOCOL>`      Defines the file transfer protocol used by the send and receive commands 
+//!                                 [default: xmodem] [possible values: xmodem, xmodem-crc, xmodem-1k, ymodem, zmodem, 
+//!                                 kermit]. Kermit uses 8th bit prefixing when the port has fewer than 8 data bits
+//! 
+//! *  `--resume`                   If given, ZMODEM transfers resume partially transferred files rather than 
+//!                                 starting them again, when sending this asks the receiver to resume
+//! 
+//! *  `--receive-dir <DIR>`        Defines the directory files received with a batch protocol (ymodem, zmodem, kermit) 
+//!                                 are saved in to [default: .]
+//! 
+//! *  `--send <FILE>`              Sends the given file with the selected protocol as soon as the port is open, 
+//!                                 then exits without entering the terminal. Can be given more than once with 
+//!                                 a batch protocol. Exits with code 1 if the transfer fails
+//! 
+//! *  `--receive`                  Receives files with the selected (batch) protocol in to the receive directory 
+//!                                 as soon as the port is open, then exits without entering the terminal. 
+//!                                 Exits with code 1 if the transfer fails
+//! 
+//! *  `--char-delay <MS>`          Defines the delay in milliseconds after each character sent by the ASCII 
+//!                                 upload command [default: 0]
+//! 
+//! *  `--line-delay <MS>`          Defines the delay in milliseconds after each line sent by the ASCII 
+//!                                 upload command [default: 0]
+//! 
+//! *  `--eol <EOL>`                Defines what the line endings of a file are translated to by the ASCII 
+//!                                 upload command [default: raw] [possible values: raw, cr, lf, crlf]
+//! 
+//! *  `--prompt <REGEX>`           If given, the ASCII upload command waits for the device to send something 
+//!                                 matching this regular expression before sending the next line
+//! 
+//! *  `--logfile <FILE>`           If given, everything received from the serial port is written to this file 
+//!                                 as is, before any escape sequences are interpreted
+//! 
+//! *  `--log-mode <LOG_MODE>`      Defines whether the log file is added to or overwritten when opened 
+//!                                 [default: append] [possible values: append, truncate]
+//! 
+//! *  `--log-sent`                 If given, everything sent to the serial port is also written to the log file
+//! 
+//! *  `--timestamp <TIMESTAMP>`    Defines what each line received is prefixed with, in the terminal and the log 
+//!                                 file, the time of day, the time since the session started, or the time since 
+//!                                 the previous line [default: off] [possible values: off, absolute, session, delta]
+//! 
+//! *  `--timestamp-format <FORMAT>` Defines the strftime format of the time of day used by `--timestamp absolute` 
+//!                                 [default: %H:%M:%S%.3f]
+//! 
+//! *  `--display <DISPLAY>`        Defines how data received is shown, as text with escape sequences interpreted, 
+//!                                 as a hex dump of every byte, or raw, passed straight through to the terminal 
+//!                                 [default: text] [possible values: text, hex, raw]
+//! 
+//! *  `--raw`                      Shorthand for `--display raw`
+//! 
+//! *  `--tab-width <COLS>`         Defines the number of columns between the tab stops the terminal starts with, 
+//!                                 the device can then set and clear them [default: 8]
+//! 
+//! *  `--charset <CHARSET>`        Defines the character set of the text received, UTF-8, or one of the 8 bit sets 
+//!                                 the bytes from 0x80 up are translated from, code page 437 or ISO 8859-1, -2, -5, 
+//!                                 -7 or -15 [default: utf8] 
+//!                                 [possible values: utf8, cp437, latin1, latin2, cyrillic, greek, latin9]
+//! 
+//! *  `--invalid-utf8 <POLICY>`    Defines how bytes received that aren't valid UTF-8 are shown, as the replacement 
+//!                                 character, as the hex value of each byte, e.g. `[ff]`, or as Latin-1 characters 
+//!                                 [default: replace] [possible values: replace, hex, latin1]
+//! 
+//! *  `--key-encoding <ENCODING>`  Defines how non-ASCII characters typed are encoded before they are sent, as UTF-8, 
+//!                                 Latin-1 or code page 437, or ascii to not send them at all and warn instead 
+//!                                 [default: utf8] [possible values: utf8, latin1, cp437, ascii]
+//! 
+//! *  `--imap <MAP>`               Defines the mappings applied to data received from the device before it is 
+//!                                 shown, as a comma separated list (see Mappings below)
+//! 
+//! *  `--omap <MAP>`               Defines the mappings applied to keys typed before they are sent to the device, 
+//!                                 as a comma separated list (see Mappings below)
+//! 
+//! *  `--emap <MAP>`               Defines the mappings applied to keys typed before they are shown by local echo, 
+//!                                 as a comma separated list (see Mappings below)
+//! 
+//! *  `--answerback <STRING>`      Defines the answerback string sent to the device when it sends ENQ, nothing 
+//!                                 is sent if it's empty [default: ""]
+//! 
+//! *  `--wait[=<SECS>]`            If the port isn't there at startup, for example a board that hasn't enumerated yet, 
+//!                                 keep trying to open it until it shows up, for up to the given number of seconds or 
+//!                                 forever if none is given. A spinner is shown while waiting, and nanocom exits with 
+//!                                 code 3 if it times out
+//! 
+//! *  `--reconnect`                If the port disconnects, for example a USB serial board resetting, keep the 
+//!                                 terminal running and reopen the port once it comes back, found by its device 
+//!                                 name or its USB identity. The baud, data bits, stop bits, parity, flow control, 
+//!                                 DTR and RTS it had are kept, including changes made while it was disconnected
+//! 
+//! *  `--list`                     List the serial ports available, with the IDs and descriptions of USB ports, 
+//!                                 and exit
+//! 
+//! *  `--json`                     Print the ports listed by `--list` as JSON, an array of objects with the 
+//!                                 name, type, vid, pid, manufacturer, product and serial_number of each port, 
+//!                                 fields that don't apply or aren't known are null
+//! 
 //! *  `-h, --help`                 Print help (see more with `--help`)
 //! 
 //! *  `-V, --version`              Print version
@@ -72,11 +177,11 @@
 //! pressing the same command key again will send that character to the serial port. 
 //! 
 //! Once command mode is entered, a command is given by pressing `Ctrl` and then a key mapped to a 
-//! command, for picocom users, **all** of these commands are the same, with `Ctrl s + r + \` being absent. 
+//! command, for picocom users, **all** of these commands are the same, with `Ctrl \` being absent. 
 //! 
 //! A confirmation message will be shown when the command has been executed and the program has excited 
-//! command mode, all commands except `Ctrl b` (change baud-rate) which requires further input, will be 
-//! executed and exit immediately. 
+//! command mode, all commands except `Ctrl b` (change baud-rate), `Ctrl s` and `Ctrl r` (file transfer) 
+//! which require further input, will be executed and exit immediately. 
 //! 
 //! If the escape  key is configured as one of the command keys, then that command will not be available. 
 //! 
@@ -85,6 +190,7 @@
 //! - `Ctrl x`
 //!     Exit the program: if the `--noreset` option was not given then the serial port is 
 //!     reset to its original settings before exiting; if it was given the serial port is not reset. 
+//!     The DTR and RTS lines can only be put back on Linux, elsewhere they are left as they are. 
 //! 
 //! - `Ctrl q`
 //!     Quit the program *without* resetting the serial port, regardless of the `--noreset` option. 
@@ -108,7 +214,7 @@
 //!     Cycle through flow-control settings (`RTS/CTS`, `XON/XOFF`, `none`). 
 //! 
 //! - `Ctrl y`
-//!     Cycle through parity settings (even, odd, none). 
+//!     Cycle through parity settings (odd, even, mark, space, none), mark and space only on Linux. 
 //! 
 //! - `Ctrl i`
 //!     Cycle through databits-number settings (`5`, `6`, `7`, `8`). 
@@ -120,6 +226,51 @@
 //!     Toggles local echo on and off. If on the application will print any and all characters typed 
 //!     to the terminal regardless of weather the serial device echoes them back. 
 //! 
+//! - `Ctrl e`
+//!     Cycle through the timestamp modes, off, time of day, time since the session started, and time 
+//!     since the previous line. 
+//! 
+//! - `Ctrl k`
+//!     Cycle between showing data received as text, as a hex dump and raw. The hex dump shows the offset, 16 
+//!     bytes per row in hex and the printable ones as ASCII, escape sequences are not interpreted so every 
+//!     byte is shown. Raw writes every byte to the terminal unmodified, without mappings or timestamps, so 
+//!     the terminal emulator renders colors and full screen programs such as `htop` or `menuconfig` itself. 
+//! 
+//! - `Ctrl n`
+//!     Change the character mappings. Shows the current mappings and prompts for which to change, 
+//!     `imap`, `omap` or `emap`, followed by the new comma separated list of mappings, e.g. 
+//!     `omap crcrlf,delbs`. Giving no mappings clears them. 
+//! 
+//! - `Ctrl s`
+//!     Send a file. Prompts for the path of the file and sends it using the protocol selected by the 
+//!     `--protocol` option, progress and retries are shown while the transfer runs. With a batch 
+//!     protocol (ymodem, zmodem, kermit) several paths separated by spaces can be given. Pressing `Ctrl [escape key]` 
+//!     during the transfer cancels it. 
+//! 
+//! - `Ctrl r`
+//!     Receive a file. Prompts for the path to save the file to and receives it using the protocol 
+//!     selected by the `--protocol` option, with a batch protocol (ymodem, zmodem, kermit) there is no prompt 
+//!     and files are saved in to the `--receive-dir` directory under the names given by the sender. 
+//!     Pressing `Ctrl [escape key]` during the transfer cancels it. 
+//! 
+//!     If the device starts a ZMODEM send (e.g. by running `sz`), nanocom spots it and starts receiving 
+//!     automatically, whichever protocol is selected. 
+//! 
+//! - `Ctrl w`
+//!     Upload a text file. Prompts for the path of the file and sends it as is, without a transfer 
+//!     protocol, for pasting scripts in to device consoles. Each character and line is followed by the 
+//!     `--char-delay` and `--line-delay`, line endings are translated as set by `--eol`, and if `--prompt` 
+//!     is given each line waits for the prompt before the next is sent. Anything the device sends back 
+//!     is shown above the progress line. Pressing `Ctrl [escape key]` during the upload cancels it. 
+//! 
+//! - `Ctrl l`
+//!     Start or stop logging to file. If no `--logfile` was given, prompts for the path of the log the 
+//!     first time logging is started. A log that was stopped is always added to when started again. 
+//! 
+//! - `Ctrl o`
+//!     Rotate the log file. The current log is moved to the first free of `<logfile>.1`, `<logfile>.2`, 
+//!     etc, and a new log is started in its place. 
+//! 
 //! - `Ctrl v`
 //!     Show program options (like baud rate, data bits, etc). Only the options that can be modified 
 //!     online (through commands) are shown, not those that can only be set at the command-line. 
@@ -127,6 +278,25 @@
 //! - `Ctrl h`
 //!     Shows a help message of all these commands, with shortened explanations of each command. 
 //! 
+//! ### Mappings
+//! 
+//! The mappings given to `--imap`, `--omap` and `--emap` (or changed with `Ctrl n`) are applied to each 
+//! character in turn, only the first that applies to a character is used. 
+//! 
+//! - `crlf` map CR to LF
+//! - `crcrlf` map CR to CR + LF
+//! - `lfcr` map LF to CR
+//! - `lfcrlf` map LF to CR + LF
+//! - `igncr` ignore CR
+//! - `ignlf` ignore LF
+//! - `bsdel` map BS to DEL
+//! - `delbs` map DEL to BS
+//! - `spchex` map special characters (other than CR, LF and TAB) to hex, e.g. `[1b]`, escape sequences 
+//!   are not interpreted when this is in the input mappings
+//! - `tabspace` map TAB to `--tab-width` spaces
+//! - `8bithex` map 8 bit characters to hex
+//! - `nrmhex` map normal ASCII characters to hex
+//! 
 //! ## Development 
 //! 
 //! I have never written a terminal emulator before, and this has all been a big yak shave originating from 
@@ -141,31 +311,21 @@
 //! 
 //! Current roadmap is:
 //! 
-//! * `--noreset / -r` flag
-//!     * This flag currently does nothing, when closing down nanocom currently just closes the port
-//!     and I'm not sure what the reset should do, it may very well be that this flag also disappears 
-//!     later. 
-//! 
-//! * File send and receive
-//!     * Picocom does this via invoking other standard unix applications, should we 
-//!     follow this lead (it would be fairly simple to reimplement them cross platform) or bundle the 
-//!     functionality within this application, this will make the interface slightly more different. 
-//! 
 //! * More rich escape sequence handling 
-//!     * We currently handle a few different cursor controls and tabs, a cursory glance reveals 
-//!     no ANSI escape sequence parsers that would really work well with this application, a custom 
-//!     one shouldn't be too difficult. 
+//!     * Escape sequences are picked out by a VT500 style parser, colors and attributes, cursor movement, 
+//!       erasing, cursor save/restore and scroll regions are acted on, the rest are still ignored. 
 //! 
 
 use core::str;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
+use std::time::Duration;
 
 use clap::Parser;
 
 use args::Args;
 use state::State;
 use inputstream::InputStream;
-use crate::key::KeyIn;
+use crate::key::{EscapeSequence, KeyIn};
 
 /// Contains the types for parsing the args at startup. 
 pub mod args;
@@ -185,15 +345,49 @@ pub mod serial_in;
 pub mod escape_handlers;
 /// Contains types and methods for handling keys in a standard way between platforms. 
 pub mod key;
+/// Contains the file transfer protocols used by the send and receive commands. 
+pub mod transfer;
+/// Contains the session log written to by the `--logfile` option and log commands. 
+pub mod logfile;
+/// Contains the picocom style character mappings applied to data in, data out and local echo. 
+pub mod mapping;
+/// Contains termios helpers for the port settings serialport doesn't cover. 
+pub mod termios;
+/// Contains the port settings saved when the port is opened and put back on exit. 
+pub mod saved;
+/// Contains types and methods for reading data from the serial port in its own thread. 
+pub mod serialstream;
+/// Contains the stand in for a disconnected port used by the `--reconnect` option. 
+pub mod reconnect;
+/// Contains the listing of the serial ports available and picking ports by USB IDs or descriptions. 
+pub mod ports;
+/// Contains the incremental parser splitting the data received in to characters and escape sequences. 
+pub mod parser;
+/// Contains the incremental UTF-8 decoder for the text received. 
+pub mod utf8;
+/// Contains the character set tables and the encoding of the keys typed. 
+pub mod charset;
+
+
+/// How long the main loop waits for a key or data before checking on a disconnected port. 
+const WAKE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most bytes received handled before checking for keys again. 
+const SERIAL_BURST: usize = 4096;
 
 
 /// The entrypoint (duh)
 fn main() {
+    let args = Args::parse();
+
+    // Nothing else is printed so the list can be parsed by scripts. 
+    if args.list {
+        std::process::exit(ports::list_ports(args.json));
+    }
+
     const VERSION: &str = env!("CARGO_PKG_VERSION");
     println!("nanocom v{}\r\n", VERSION);
 
-    let args = Args::parse();
-
     let mut state = match State::new_from_args(&args) {
         Ok(v) => v,
         Err(_) => return
@@ -201,39 +395,84 @@ fn main() {
 
     println!("{}", args.show_state());
 
+    if !args.send.is_empty() || args.receive {
+        let code = unattended_transfer(&args, &mut state);
+        state.reset_port();
+        std::process::exit(code);
+    }
+
     println!("Type [C-{}] [C-h] to see available commands", args.escape);
     println!("Terminal ready");
 
     let _ = main_event_loop(&mut state);
+    state.flush_log();
+    escape_handlers::reset_terminal(&mut state);
+    state.reset_port();
     println!("Thank you for using nanocom");
 }
 
-/// Main event loop, continuously polls user and serial port input, processing and forwarding data between the 2. 
+/// Runs the file transfer asked for by the `--send` or `--receive` args without entering the 
+/// terminal, returning the exit code. 
+fn unattended_transfer(args: &Args, state: &mut State) -> i32 {
+    let res = if args.receive {
+        transfer::receive_files(None, state, None)
+    } else {
+        transfer::send_files(&args.send, state, None)
+    };
+    match res {
+        Ok(bytes) => {
+            println!("\r\n*** Transferred {} bytes ***", bytes);
+            0
+        },
+        Err(e) => {
+            println!("\r\n*** Transfer failed, {} ", e);
+            1
+        }
+    }
+}
+
+/// Main event loop, waits for user and serial port input, processing and forwarding data between the 2. 
 /// Exiting when a quit command is received or an unrecoverable error is encountered. 
 fn main_event_loop(state: &mut State) -> Result<(), ()> {
-    let input_stream = InputStream::new(state.escape_code, vec![24, 17]);
+    let input_stream = InputStream::new(state.escape_code, vec![24, 17], state.waker.clone(), 
+        state.cursor_reports.clone());
 
     loop {
-        poll_input(state, &input_stream)?;
-        let res = serial_in::poll_port_parse_data(state)?;
-        let _ = terminal::print_data_in(res, state);
+        if !state.serial.has_data() {
+            let wait = serial_in::time_left(state).map_or(WAKE_INTERVAL, |v| v.min(WAKE_INTERVAL));
+            let _ = state.wake.recv_timeout(wait);
+        }
+        while state.wake.try_recv().is_ok() {}
+        state.try_reconnect();
+        while poll_input(state, &input_stream)? {}
+        for _ in 0..SERIAL_BURST {
+            if !state.serial.has_data() { break }
+            let res = serial_in::poll_port_parse_data(state)?;
+            let _ = terminal::print_data_in(res, state);
+            if state.zmodem_autostart {
+                commands::autostart_zmodem(state, &input_stream);
+            }
+        }
+        serial_in::flush_stale_sequence(state);
+        state.flush_log();
     }
 }
 
-/// Checks if there is any user input received from the input stream, acting upon it if so or returning if not. 
-fn poll_input(state: &mut State, input_stream: &InputStream) -> Result<(), ()> {
+/// Checks if there is any user input received from the input stream, acting upon it if so, returns 
+/// whether there was any. 
+fn poll_input(state: &mut State, input_stream: &InputStream) -> Result<bool, ()> {
     let v = match input_stream.get_char() {
         Some(Ok(v)) => v,
         Some(Err(_)) => {
             println!("*** Input stream disconnected exiting. ");
             return Err(());
         },
-        None => return  Ok(())
+        None => return Ok(false)
     };
     if let Err(HandleInputError::Shutdown) = handle_input(v, state, &input_stream) {
         return Err(());
     }
-    Ok(())
+    Ok(true)
 }
 
 /// Handles a key received from the input. 
@@ -241,15 +480,37 @@ fn handle_input(key: KeyIn,
     state: &mut State, 
     input_stream: &InputStream) -> Result<(), HandleInputError> 
 {
+    if let KeyIn::Escape(EscapeSequence::CursorReport(row, col)) = key {
+        escape_handlers::reply_cursor_position(row, col, state);
+        return Ok(());
+    }
     let seq = key.to_bytes();
     if seq.len() == 0 { return Ok(()); }
     
     if commands::handle_escape(&seq, state, input_stream)? { return Ok(()) }
+    let seq = match key {
+        KeyIn::Char(c) => mapping::map_byte(c, &state.omap, state.tab_width as usize),
+        KeyIn::Unicode(c) => match charset::encode(c, state.key_encoding) {
+            Some(v) => v,
+            None => {
+                println!("\r\n*** Can't send '{}' as {}, not sent \r\n", c, state.key_encoding.show());
+                return Ok(());
+            }
+        },
+        _ => seq
+    };
     if state.local_echo {
-        let _ = terminal::print_data_in(key.clone(), state);
+        let _ = terminal::echo_key(key, state);
     }
-    match state.port.write(&seq) {
-        Ok(_) => Ok(()),
+    if seq.is_empty() { return Ok(()); }
+    match state.port.write_all(&seq) {
+        Ok(_) => {
+            if state.log_sent { state.log(&seq); }
+            Ok(())
+        },
+        Err(e) if state.reconnect && state.disconnected.is_none() && e.kind() != ErrorKind::TimedOut => {
+            if state.disconnect(&e) { Ok(()) } else { Err(HandleInputError::Shutdown) }
+        },
         Err(e) => {
             println!("\r\n*** Failed to write to port, reason: \r\n{}", e);
             Err(HandleInputError::FailedToWrite)
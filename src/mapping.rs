@@ -0,0 +1,32 @@
+use crate::args::Map;
+
+
+/// Applies the mappings to a single byte, returning what it should be replaced with, which may be
+/// nothing if it's ignored or several bytes if it's expanded.
+///
+/// Only the first mapping that applies to a byte is used, bytes shown as hex are replaced with
+/// `[xx]` and tabs mapped to spaces with `tab_width` of them.
+pub fn map_byte(byte: u8, maps: &[Map], tab_width: usize) -> Vec<u8> {
+    let has = |m: Map| maps.contains(&m);
+    match byte {
+        b'\r' if has(Map::IgnCr) => vec![],
+        b'\r' if has(Map::CrLf) => vec![b'\n'],
+        b'\r' if has(Map::CrCrLf) => vec![b'\r', b'\n'],
+        b'\n' if has(Map::IgnLf) => vec![],
+        b'\n' if has(Map::LfCr) => vec![b'\r'],
+        b'\n' if has(Map::LfCrLf) => vec![b'\r', b'\n'],
+        0x08 if has(Map::BsDel) => vec![0x7F],
+        0x7F if has(Map::DelBs) => vec![0x08],
+        b'\t' if has(Map::TabSpace) => vec![b' '; tab_width],
+        b'\r' | b'\n' | b'\t' => vec![byte],
+        0x00..=0x1F | 0x7F if has(Map::SpcHex) => to_hex(byte),
+        0x20..=0x7E if has(Map::NrmHex) => to_hex(byte),
+        0x80..=0xFF if has(Map::EightBitHex) => to_hex(byte),
+        _ => vec![byte],
+    }
+}
+
+/// Gives the `[xx]` form of a byte shown as hex.
+fn to_hex(byte: u8) -> Vec<u8> {
+    format!("[{:02x}]", byte).into_bytes()
+}
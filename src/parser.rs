@@ -0,0 +1,527 @@
+use std::time::{Duration, Instant};
+
+use crate::key::{Color, EscapeSequence, GraphicSet, KeyIn, Sgr};
+
+
+/// How long a sequence may go without another byte before it's given up on and shown as is.
+const SEQUENCE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The most bytes of a sequence kept, anything longer is still parsed but not kept.
+const MAX_SEQUENCE: usize = 4096;
+
+/// The most parameters kept for a control sequence.
+const MAX_PARAMS: usize = 16;
+
+/// Where the parser is in a sequence, following the states of the DEC VT500 parser.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    DcsEntry,
+    DcsParam,
+    DcsIntermediate,
+    DcsPassthrough,
+    DcsIgnore,
+    OscString,
+    SosPmApcString,
+}
+
+impl ParseState {
+    /// Is this one of the states for a string ended by ST (`ESC \`).
+    fn is_string(&self) -> bool {
+        matches!(self, ParseState::DcsPassthrough | ParseState::DcsIgnore | ParseState::OscString | ParseState::SosPmApcString)
+    }
+
+    /// Is this one of the states where controls are acted on straight away rather than being part
+    /// of the sequence.
+    fn executes_controls(&self) -> bool {
+        use ParseState::*;
+        matches!(self, Escape | EscapeIntermediate | CsiEntry | CsiParam | CsiIntermediate | CsiIgnore)
+    }
+}
+
+/// Splits the data received in to characters and escape sequences a byte at a time, so a sequence
+/// split across reads, or never finished, doesn't hold anything up.
+///
+/// Follows the DEC VT500 parser: controls inside a sequence are acted on straight away, CAN and
+/// SUB abandon a sequence and ESC starts a new one. Bytes above `0x7F` are passed through as
+/// characters rather than treated as 8 bit controls, so UTF-8 text isn't mangled.
+pub struct Parser {
+    /// Where the parser is in the current sequence.
+    state: ParseState,
+    /// The bytes of the current sequence, given back as is if it times out.
+    raw: Vec<u8>,
+    /// The numeric parameters of the current control sequence, missing ones are 0.
+    params: Vec<u16>,
+    /// Whether each parameter was joined on to the one before with a `:`, as a sub parameter.
+    joined: Vec<bool>,
+    /// The intermediate and private marker bytes of the current sequence.
+    intermediates: Vec<u8>,
+    /// An ESC was received inside a string, which is ended if the next byte is `\`.
+    string_esc: bool,
+    /// When the last byte of the current sequence was received.
+    last: Instant,
+}
+
+impl Parser {
+
+    /// Creates a parser outside of any sequence.
+    pub fn new() -> Parser {
+        Parser {
+            state: ParseState::Ground,
+            raw: Vec::new(),
+            params: Vec::new(),
+            joined: Vec::new(),
+            intermediates: Vec::new(),
+            string_esc: false,
+            last: Instant::now(),
+        }
+    }
+
+    /// Feeds in a byte received, giving what it completes: the byte itself outside of a sequence
+    /// or if it's a control acted on inside one, the sequence once it's finished, or nothing while
+    /// a sequence is still going.
+    pub fn advance(&mut self, byte: u8) -> KeyIn {
+        self.last = Instant::now();
+        if self.string_esc {
+            self.string_esc = false;
+            if byte == b'\\' {
+                self.keep(byte);
+                return self.finish(EscapeSequence::UnknownSeq(self.raw.clone()));
+            }
+            self.start();
+        }
+        match byte {
+            0x18 | 0x1A => {
+                self.reset();
+                return KeyIn::Char(byte);
+            },
+            0x1B if self.state.is_string() => {
+                self.keep(byte);
+                self.string_esc = true;
+                return KeyIn::Nothing;
+            },
+            0x1B => {
+                self.start();
+                return KeyIn::Nothing;
+            },
+            _ => ()
+        }
+        if self.state == ParseState::Ground { return KeyIn::Char(byte) }
+        if byte < 0x20 && self.state.executes_controls() { return KeyIn::Char(byte) }
+        self.keep(byte);
+        match self.state {
+            ParseState::Ground => KeyIn::Char(byte),
+            ParseState::Escape => self.escape(byte),
+            ParseState::EscapeIntermediate => self.escape_intermediate(byte),
+            ParseState::CsiEntry | ParseState::CsiParam | ParseState::CsiIntermediate | ParseState::CsiIgnore => self.csi(byte),
+            ParseState::DcsEntry | ParseState::DcsParam | ParseState::DcsIntermediate => self.dcs(byte),
+            ParseState::DcsPassthrough | ParseState::DcsIgnore | ParseState::SosPmApcString => KeyIn::Nothing,
+            ParseState::OscString => self.osc(byte),
+        }
+    }
+
+    /// Gives up on a sequence that hasn't had a byte in too long, giving back its bytes to be
+    /// shown as they are, empty if there isn't one.
+    pub fn flush_stale(&mut self) -> Vec<u8> {
+        if self.state == ParseState::Ground || self.last.elapsed() < SEQUENCE_TIMEOUT { return vec![] }
+        let raw = std::mem::take(&mut self.raw);
+        self.reset();
+        raw
+    }
+
+    /// How long until the current sequence times out, `None` outside of a sequence.
+    pub fn time_left(&self) -> Option<Duration> {
+        if self.state == ParseState::Ground { return None }
+        Some(SEQUENCE_TIMEOUT.saturating_sub(self.last.elapsed()))
+    }
+
+    /// Handles a byte following an ESC.
+    fn escape(&mut self, byte: u8) -> KeyIn {
+        match byte {
+            0x20..=0x2F => {
+                self.intermediates.push(byte);
+                self.state = ParseState::EscapeIntermediate;
+                KeyIn::Nothing
+            },
+            b'[' => self.enter(ParseState::CsiEntry),
+            b']' => self.enter(ParseState::OscString),
+            b'P' => self.enter(ParseState::DcsEntry),
+            b'X' | b'^' | b'_' => self.enter(ParseState::SosPmApcString),
+            b'7' => self.finish(EscapeSequence::SaveCursor),
+            b'8' => self.finish(EscapeSequence::RestoreCursor),
+            b'M' => self.finish(EscapeSequence::ReverseIndex),
+            b'H' => self.finish(EscapeSequence::SetTabStop),
+            0x30..=0x7E => self.finish(EscapeSequence::UnknownSeq(self.raw.clone())),
+            _ => KeyIn::Nothing
+        }
+    }
+
+    /// Handles a byte after the intermediates of an escape sequence have started.
+    fn escape_intermediate(&mut self, byte: u8) -> KeyIn {
+        match byte {
+            0x20..=0x2F => {
+                self.intermediates.push(byte);
+                KeyIn::Nothing
+            },
+            0x30..=0x7E => {
+                let seq = self.escape_dispatch(byte);
+                self.finish(seq)
+            },
+            _ => KeyIn::Nothing
+        }
+    }
+
+    /// Gives the escape sequence with intermediates that's been finished by the final byte.
+    fn escape_dispatch(&self, byte: u8) -> EscapeSequence {
+        let slot = match self.intermediates[..] {
+            [b'('] => 0,
+            [b')'] => 1,
+            _ => return EscapeSequence::UnknownSeq(self.raw.clone())
+        };
+        match byte {
+            b'B' => EscapeSequence::DesignateCharset(slot, GraphicSet::Ascii),
+            b'A' => EscapeSequence::DesignateCharset(slot, GraphicSet::Uk),
+            b'0' => EscapeSequence::DesignateCharset(slot, GraphicSet::DecSpecialGraphics),
+            _ => EscapeSequence::UnknownSeq(self.raw.clone())
+        }
+    }
+
+    /// Handles a byte of a control sequence.
+    fn csi(&mut self, byte: u8) -> KeyIn {
+        use ParseState::*;
+        match (self.state, byte) {
+            (CsiIgnore, 0x40..=0x7E) => return self.finish(EscapeSequence::Invalid),
+            (CsiIgnore, _) => (),
+            (CsiEntry | CsiParam, b'0'..=b'9' | b';' | b':') => {
+                self.param(byte);
+                self.state = CsiParam;
+            },
+            (CsiEntry, 0x3C..=0x3F) => {
+                self.intermediates.push(byte);
+                self.state = CsiParam;
+            },
+            (_, 0x20..=0x2F) => {
+                self.intermediates.push(byte);
+                self.state = CsiIntermediate;
+            },
+            (_, 0x30..=0x3F) => self.state = CsiIgnore,
+            (_, 0x40..=0x7E) => {
+                let seq = self.csi_dispatch(byte);
+                return self.finish(seq);
+            },
+            _ => ()
+        }
+        KeyIn::Nothing
+    }
+
+    /// Handles a byte of the start of a device control string, before its data.
+    fn dcs(&mut self, byte: u8) -> KeyIn {
+        use ParseState::*;
+        match (self.state, byte) {
+            (DcsEntry | DcsParam, b'0'..=b'9' | b';') => {
+                self.param(byte);
+                self.state = DcsParam;
+            },
+            (DcsEntry, 0x3C..=0x3F) => {
+                self.intermediates.push(byte);
+                self.state = DcsParam;
+            },
+            (_, 0x20..=0x2F) => {
+                self.intermediates.push(byte);
+                self.state = DcsIntermediate;
+            },
+            (_, 0x30..=0x3F) => self.state = DcsIgnore,
+            (_, 0x40..=0x7E) => self.state = DcsPassthrough,
+            _ => ()
+        }
+        KeyIn::Nothing
+    }
+
+    /// Handles a byte of an operating system command, which is ended by BEL as well as ST. The 8 bit
+    /// ST (`0x9C`) isn't used as it can be part of a UTF-8 character.
+    fn osc(&mut self, byte: u8) -> KeyIn {
+        match byte {
+            0x07 => self.finish(EscapeSequence::UnknownSeq(self.raw.clone())),
+            _ => KeyIn::Nothing
+        }
+    }
+
+    /// Adds a digit or separator to the parameters of a sequence.
+    fn param(&mut self, byte: u8) {
+        if self.params.is_empty() {
+            self.params.push(0);
+            self.joined.push(false);
+        }
+        match byte {
+            b';' | b':' => if self.params.len() < MAX_PARAMS {
+                self.params.push(0);
+                self.joined.push(byte == b':');
+            },
+            _ => if let Some(last) = self.params.last_mut() {
+                *last = last.saturating_mul(10).saturating_add((byte - b'0') as u16);
+            }
+        }
+    }
+
+    /// Gives the sequence for a finished control sequence.
+    fn csi_dispatch(&self, byte: u8) -> EscapeSequence {
+        if !self.intermediates.is_empty() { return EscapeSequence::UnknownSeq(self.raw.clone()) }
+        let first = self.params.first().copied().unwrap_or(0);
+        match (self.params.as_slice(), byte) {
+            (_, b'A') => EscapeSequence::CursorUp(self.count(0)),
+            (_, b'B') => EscapeSequence::CursorDown(self.count(0)),
+            (_, b'C') => EscapeSequence::CursorForward(self.count(0)),
+            (_, b'D') => EscapeSequence::CursorBack(self.count(0)),
+            (_, b'H' | b'f') => EscapeSequence::CursorPosition(self.count(0), self.count(1)),
+            (_, b'J') => EscapeSequence::EraseDisplay(first),
+            (_, b'K') => EscapeSequence::EraseLine(first),
+            (_, b'r') => EscapeSequence::ScrollRegion(first, self.params.get(1).copied().unwrap_or(0)),
+            ([], b's') => EscapeSequence::SaveCursor,
+            ([], b'u') => EscapeSequence::RestoreCursor,
+            (_, b'Z') => EscapeSequence::CursorBackTab(self.count(0)),
+            (_, b'g') => EscapeSequence::ClearTabStop(first),
+            ([] | [0], b'c') => EscapeSequence::DeviceAttributes,
+            ([5], b'n') => EscapeSequence::DeviceStatus,
+            ([6], b'n') => EscapeSequence::ReportCursorPosition,
+            (_, b'm') => EscapeSequence::Sgr(self.sgr()),
+            _ => EscapeSequence::UnknownSeq(self.raw.clone())
+        }
+    }
+
+    /// The count given by the parameter at `index`, which is 1 if it's missing or 0.
+    fn count(&self, index: usize) -> u16 {
+        self.params.get(index).copied().unwrap_or(0).max(1)
+    }
+
+    /// Gives the attribute changes of a Select Graphic Rendition sequence, unknown ones are skipped.
+    fn sgr(&self) -> Vec<Sgr> {
+        if self.params.is_empty() { return vec![Sgr::Reset] }
+        let mut res = Vec::new();
+        let mut i = 0;
+        while i < self.params.len() {
+            let mut next = i + 1;
+            while next < self.params.len() && self.joined[next] { next += 1 }
+            let sub = &self.params[i + 1..next];
+            let attr = match self.params[i] {
+                0 => Some(Sgr::Reset),
+                1 => Some(Sgr::Bold),
+                2 => Some(Sgr::Dim),
+                3 => Some(Sgr::Italic),
+                4 if sub == [0] => Some(Sgr::NoUnderline),
+                4 | 21 => Some(Sgr::Underline),
+                5 | 6 => Some(Sgr::Blink),
+                7 => Some(Sgr::Reverse),
+                8 => Some(Sgr::Hidden),
+                9 => Some(Sgr::Strikethrough),
+                22 => Some(Sgr::NormalIntensity),
+                23 => Some(Sgr::NoItalic),
+                24 => Some(Sgr::NoUnderline),
+                25 => Some(Sgr::NoBlink),
+                27 => Some(Sgr::NoReverse),
+                28 => Some(Sgr::NoHidden),
+                29 => Some(Sgr::NoStrikethrough),
+                v @ 30..=37 => Some(Sgr::Foreground(Color::Indexed((v - 30) as u8))),
+                v @ 90..=97 => Some(Sgr::Foreground(Color::Indexed((v - 90 + 8) as u8))),
+                v @ 40..=47 => Some(Sgr::Background(Color::Indexed((v - 40) as u8))),
+                v @ 100..=107 => Some(Sgr::Background(Color::Indexed((v - 100 + 8) as u8))),
+                39 => Some(Sgr::DefaultForeground),
+                49 => Some(Sgr::DefaultBackground),
+                v @ (38 | 48) => {
+                    // Either 38:5:N or 38:2:[ID:]R:G:B as sub parameters, or 38;5;N or 38;2;R;G;B
+                    let color = if sub.is_empty() {
+                        let (color, used) = extended_color(&self.params[next..]);
+                        next += used;
+                        color
+                    } else {
+                        match sub {
+                            [5, n] => Some(Color::Indexed(clamp(*n))),
+                            [2, .., r, g, b] => Some(Color::Rgb(clamp(*r), clamp(*g), clamp(*b))),
+                            _ => None
+                        }
+                    };
+                    color.map(|c| if v == 38 { Sgr::Foreground(c) } else { Sgr::Background(c) })
+                },
+                _ => None
+            };
+            res.extend(attr);
+            i = next;
+        }
+        res
+    }
+
+    /// Starts a new sequence on an ESC, abandoning any current one.
+    fn start(&mut self) {
+        self.reset();
+        self.raw.push(0x1B);
+        self.state = ParseState::Escape;
+    }
+
+    /// Moves on to the given state from an escape.
+    fn enter(&mut self, state: ParseState) -> KeyIn {
+        self.state = state;
+        KeyIn::Nothing
+    }
+
+    /// Ends the current sequence, giving what it was.
+    fn finish(&mut self, seq: EscapeSequence) -> KeyIn {
+        self.reset();
+        KeyIn::Escape(seq)
+    }
+
+    /// Keeps a byte of the current sequence, unless it's too long already.
+    fn keep(&mut self, byte: u8) {
+        if self.raw.len() < MAX_SEQUENCE { self.raw.push(byte) }
+    }
+
+    /// Goes back to outside of any sequence.
+    fn reset(&mut self) {
+        self.state = ParseState::Ground;
+        self.raw.clear();
+        self.params.clear();
+        self.joined.clear();
+        self.intermediates.clear();
+        self.string_esc = false;
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new()
+    }
+}
+
+/// Reads an extended color given as separate parameters after a 38 or 48, giving the color and
+/// how many parameters it used, all of them if it's not valid.
+fn extended_color(params: &[u16]) -> (Option<Color>, usize) {
+    match params {
+        [5, n, ..] => (Some(Color::Indexed(clamp(*n))), 2),
+        [2, r, g, b, ..] => (Some(Color::Rgb(clamp(*r), clamp(*g), clamp(*b))), 4),
+        _ => (None, params.len())
+    }
+}
+
+/// Limits a parameter to a byte.
+fn clamp(v: u16) -> u8 {
+    v.min(255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A xorshift generator, so the streams are random but the same every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    /// Picks a byte, mostly ones that start, continue or end sequences.
+    fn random_byte(rng: &mut Rng) -> u8 {
+        const INTERESTING: &[u8] = b"\x1b\x1b\x1b[[]P^X_\\()0AB;:0123456789?>~ACHJKZcmnr\x07\x08\x0a\x0d\x18\x1a\x7f\x9c ";
+        if rng.next().is_multiple_of(3) {
+            rng.next() as u8
+        } else {
+            INTERESTING[rng.next() as usize % INTERESTING.len()]
+        }
+    }
+
+    /// Feeds in a byte, checking it either comes straight back out or is kept as part of the
+    /// current sequence, never both.
+    fn feed(p: &mut Parser, byte: u8) {
+        let before = p.raw.clone();
+        let in_string = p.state.is_string();
+        let string_esc = p.string_esc;
+        let res = p.advance(byte);
+
+        // What the current sequence should hold if the byte was kept
+        let mut seq = if string_esc && byte != b'\\' || byte == 0x1B && !in_string {
+            vec![0x1B]
+        } else {
+            before.clone()
+        };
+        if byte != 0x1B || in_string && !string_esc { seq.push(byte) }
+        seq.truncate(MAX_SEQUENCE);
+
+        match res {
+            KeyIn::Char(c) => {
+                assert_eq!(c, byte);
+                assert!(p.raw == before || p.raw.is_empty() || p.raw == [0x1B], "{:?} kept as well as given back", byte);
+            },
+            KeyIn::Nothing => assert_eq!(p.raw, seq),
+            KeyIn::Escape(EscapeSequence::UnknownSeq(v)) => {
+                assert_eq!(v, seq);
+                assert!(p.raw.is_empty());
+            },
+            KeyIn::Escape(_) => assert!(p.raw.is_empty()),
+            KeyIn::Unicode(_) => panic!("the parser never gives characters"),
+        }
+    }
+
+    /// Flushes the current sequence as if it had timed out, checking every byte kept comes out.
+    fn flush(p: &mut Parser) {
+        p.last = Instant::now() - SEQUENCE_TIMEOUT;
+        let raw = p.raw.clone();
+        let ground = p.state == ParseState::Ground;
+        let out = p.flush_stale();
+        assert_eq!(out, if ground { vec![] } else { raw });
+        assert_eq!(p.state, ParseState::Ground);
+        assert!(p.raw.is_empty());
+        assert_eq!(p.time_left(), None);
+    }
+
+    #[test]
+    fn random_streams() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let mut p = Parser::new();
+            let len = rng.next() % 300;
+            for _ in 0..len {
+                feed(&mut p, random_byte(&mut rng));
+                if rng.next().is_multiple_of(64) { flush(&mut p) }
+            }
+            flush(&mut p);
+        }
+    }
+
+    #[test]
+    fn long_strings_are_cut_short() {
+        let mut p = Parser::new();
+        feed(&mut p, 0x1B);
+        feed(&mut p, b']');
+        for _ in 0..MAX_SEQUENCE * 2 { feed(&mut p, b'x') }
+        assert_eq!(p.raw.len(), MAX_SEQUENCE);
+        flush(&mut p);
+    }
+
+    #[test]
+    fn controls_inside_a_sequence_come_out_once() {
+        let mut p = Parser::new();
+        for b in *b"\x1b[1" { assert!(matches!(p.advance(b), KeyIn::Nothing)) }
+        for b in *b"\x07\r\n" { assert!(matches!(p.advance(b), KeyIn::Char(c) if c == b)) }
+        p.last = Instant::now() - SEQUENCE_TIMEOUT;
+        assert_eq!(p.flush_stale(), b"\x1b[1");
+    }
+
+    #[test]
+    fn finished_sequences() {
+        let mut p = Parser::new();
+        let mut parse = |data: &[u8]| data.iter().map(|b| p.advance(*b)).last().unwrap();
+        assert!(matches!(parse(b"\x1b[12;34H"), KeyIn::Escape(EscapeSequence::CursorPosition(12, 34))));
+        assert!(matches!(parse(b"\x1b[\r2J"), KeyIn::Escape(EscapeSequence::EraseDisplay(2))));
+        assert!(matches!(parse(b"\x1b(0"), KeyIn::Escape(EscapeSequence::DesignateCharset(0, GraphicSet::DecSpecialGraphics))));
+        assert!(matches!(parse(b"\x1b]0;title\x07"), KeyIn::Escape(EscapeSequence::UnknownSeq(v)) if v == b"\x1b]0;title\x07"));
+        assert!(matches!(parse(b"\x1bP1$r\x1b\\"), KeyIn::Escape(EscapeSequence::UnknownSeq(v)) if v == b"\x1bP1$r\x1b\\"));
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+
+/// Prints the serial ports available on the system, as JSON if `json` is true, returning the
+/// exit code.
+pub fn list_ports(json: bool) -> i32 {
+    let ports = match serialport::available_ports() {
+        Ok(v) => v,
+        Err(e) => {
+            println!("*** Failed to list serial ports, reason: \r\n{}", e.description);
+            return 1;
+        }
+    };
+    if json {
+        println!("{}", ports_json(&ports));
+    } else if ports.is_empty() {
+        println!("No serial ports found");
+    } else {
+        for port in &ports {
+            print!("{}", port_text(port));
+        }
+    }
+    0
+}
+
+/// Resolves the port given to the name of a device, it can be the name of a device or one of the 
+/// selectors:
+///
+/// * `usb:VID:PID` or `usb:VID` - A USB port with the given hex vendor and product IDs.
+/// * `serial:SERIAL` - A USB port with the given serial number.
+/// * `desc:TEXT` - A USB port with a manufacturer or product description containing the text.
+///
+/// Gives `None` if a selector matches no ports, as it may not have shown up yet.
+pub fn resolve_port(spec: &str) -> Result<Option<String>, ResolveError> {
+    let matcher = match Selector::parse(spec) {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(Some(spec.to_string())),
+        Err(e) => return Err(ResolveError::Invalid(spec.to_string(), e))
+    };
+    let ports = serialport::available_ports().map_err(|e| ResolveError::List(e.description))?;
+    let mut found: Vec<SerialPortInfo> = ports.into_iter().filter(|p| matcher.matches(p)).collect();
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(Some(found.remove(0).port_name)),
+        _ => Err(ResolveError::Ambiguous(spec.to_string(), found))
+    }
+}
+
+/// Why [resolve_port] couldn't resolve a port selector.
+pub enum ResolveError {
+    /// The selector given can't be parsed, with the reason why.
+    Invalid(String, &'static str),
+    /// The serial ports couldn't be listed, with the reason why.
+    List(String),
+    /// The selector given matches more than one port, with the ports it matches.
+    Ambiguous(String, Vec<SerialPortInfo>),
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Invalid(spec, reason) => write!(f, "Invalid port selector {}, {}", spec, reason),
+            ResolveError::List(reason) => write!(f, "Failed to list serial ports, reason: \r\n{}", reason),
+            ResolveError::Ambiguous(spec, found) => {
+                write!(f, "More than one serial port matches {}, candidates are: \r\n\r\n", spec)?;
+                found.iter().try_for_each(|port| write!(f, "{}", port_text(port)))
+            }
+        }
+    }
+}
+
+/// Finds the USB identity of the port with the given device name, `None` if it isn't a USB port.
+pub fn usb_identity(name: &str) -> Option<UsbPortInfo> {
+    let ports = serialport::available_ports().ok()?;
+    ports.into_iter().find_map(|p| match p.port_type {
+        SerialPortType::UsbPort(v) if p.port_name == name => Some(v),
+        _ => None
+    })
+}
+
+/// Looks for a port that disconnected, by its USB identity if it had one, as another device may 
+/// have taken its name, or else by its device name, returning the name to reopen it with. Where 
+/// more than one port has the identity, only the one with the same name is picked.
+pub fn find_again(name: &str, usb: Option<&UsbPortInfo>) -> Option<String> {
+    let usb = match usb {
+        Some(v) => v,
+        None if Path::new(name).exists() => return Some(name.to_string()),
+        None => {
+            let ports = serialport::available_ports().ok()?;
+            return ports.into_iter().find(|p| p.port_name == name).map(|p| p.port_name);
+        }
+    };
+    let ports = serialport::available_ports().ok()?;
+    let found: Vec<SerialPortInfo> = ports.into_iter().filter(|p| match &p.port_type {
+        SerialPortType::UsbPort(v) => v.vid == usb.vid && v.pid == usb.pid && v.serial_number == usb.serial_number,
+        _ => false
+    }).collect();
+    match found.as_slice() {
+        [p] => Some(p.port_name.clone()),
+        _ => found.into_iter().find(|p| p.port_name == name).map(|p| p.port_name)
+    }
+}
+
+/// A way of picking a port other than by the name of its device.
+enum Selector {
+    /// A USB vendor ID and optionally product ID.
+    Usb(u16, Option<u16>),
+    /// A USB serial number.
+    Serial(String),
+    /// Text in a USB manufacturer or product description, in lower case.
+    Desc(String),
+}
+
+impl Selector {
+    /// Parses a port selector, returning `None` if the port given is just a device name.
+    fn parse(spec: &str) -> Result<Option<Selector>, &'static str> {
+        let (kind, value) = match spec.split_once(':') {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let value = value.trim_matches('"');
+        let hex = |v: &str| u16::from_str_radix(v, 16).map_err(|_| "IDs should be 4 hex digits");
+        let selector = match kind {
+            "usb" => match value.split_once(':') {
+                Some((vid, pid)) => Selector::Usb(hex(vid)?, Some(hex(pid)?)),
+                None => Selector::Usb(hex(value)?, None),
+            },
+            "serial" => Selector::Serial(value.to_string()),
+            "desc" => Selector::Desc(value.to_lowercase()),
+            _ => return Ok(None)
+        };
+        Ok(Some(selector))
+    }
+
+    /// Checks if the port is picked by the selector, only USB ports can be.
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        let usb = match &port.port_type {
+            SerialPortType::UsbPort(v) => v,
+            _ => return false
+        };
+        let contains = |v: &Option<String>, text: &str| v.as_ref().is_some_and(|v| v.to_lowercase().contains(text));
+        match self {
+            Selector::Usb(vid, pid) => usb.vid == *vid && pid.is_none_or(|pid| usb.pid == pid),
+            Selector::Serial(serial) => usb.serial_number.as_deref() == Some(serial.as_str()),
+            Selector::Desc(text) => contains(&usb.manufacturer, text) || contains(&usb.product, text),
+        }
+    }
+}
+
+/// The human readable name of the type of port.
+fn type_name(port_type: &SerialPortType) -> &'static str {
+    match port_type {
+        SerialPortType::UsbPort(_) => "usb",
+        SerialPortType::PciPort => "pci",
+        SerialPortType::BluetoothPort => "bluetooth",
+        SerialPortType::Unknown => "unknown",
+    }
+}
+
+/// Describes a port over several lines, USB ports have their IDs and descriptions included.
+fn port_text(port: &SerialPortInfo) -> String {
+    let mut res = format!("{}\r\n    type          : {}\r\n", port.port_name, type_name(&port.port_type));
+    if let SerialPortType::UsbPort(usb) = &port.port_type {
+        let show = |v: &Option<String>| v.clone().unwrap_or("unknown".to_string());
+        res += &format!("    vid:pid       : {:04x}:{:04x}\r\n", usb.vid, usb.pid);
+        res += &format!("    manufacturer  : {}\r\n", show(&usb.manufacturer));
+        res += &format!("    product       : {}\r\n", show(&usb.product));
+        res += &format!("    serial number : {}\r\n", show(&usb.serial_number));
+    }
+    res
+}
+
+/// Describes the ports as a JSON array of objects, fields that only apply to USB ports or
+/// aren't known are `null`.
+fn ports_json(ports: &[SerialPortInfo]) -> String {
+    let objects: Vec<String> = ports.iter().map(|port| {
+        let usb = match &port.port_type {
+            SerialPortType::UsbPort(v) => Some(v),
+            _ => None
+        };
+        let hex = |id: Option<u16>| id.map(|v| format!("{:04x}", v));
+        format!("{{\"name\":{},\"type\":{},\"vid\":{},\"pid\":{},\"manufacturer\":{},\"product\":{},\"serial_number\":{}}}",
+            json_string(Some(&port.port_name)),
+            json_string(Some(type_name(&port.port_type))),
+            json_string(hex(usb.map(|v| v.vid)).as_deref()),
+            json_string(hex(usb.map(|v| v.pid)).as_deref()),
+            json_string(usb.and_then(|v| v.manufacturer.as_deref())),
+            json_string(usb.and_then(|v| v.product.as_deref())),
+            json_string(usb.and_then(|v| v.serial_number.as_deref())),
+        )
+    }).collect();
+    format!("[{}]", objects.join(","))
+}
+
+/// Quotes and escapes a string for JSON, `None` is given as `null`.
+fn json_string(value: Option<&str>) -> String {
+    let value = match value {
+        Some(v) => v,
+        None => return "null".to_string()
+    };
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            '\n' => res += "\\n",
+            '\r' => res += "\\r",
+            '\t' => res += "\\t",
+            c if (c as u32) < 0x20 => res += &format!("\\u{:04x}", c as u32),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Describes a USB port with the IDs and descriptions given.
+    fn usb_port(vid: u16, pid: u16, serial: &str, manufacturer: &str, product: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: "/dev/ttyUSB0".to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial.to_string()),
+                manufacturer: Some(manufacturer.to_string()),
+                product: Some(product.to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn usb_selectors() {
+        assert!(matches!(Selector::parse("usb:0403"), Ok(Some(Selector::Usb(0x0403, None)))));
+        assert!(matches!(Selector::parse("usb:0403:6001"), Ok(Some(Selector::Usb(0x0403, Some(0x6001))))));
+        assert!(matches!(Selector::parse("usb:10C4:ea60"), Ok(Some(Selector::Usb(0x10c4, Some(0xea60))))));
+        assert!(Selector::parse("usb:zz").is_err());
+        assert!(Selector::parse("usb:0403:").is_err());
+        assert!(Selector::parse("usb:12345").is_err());
+    }
+
+    #[test]
+    fn text_selectors() {
+        assert!(matches!(Selector::parse("serial:A12B"), Ok(Some(Selector::Serial(v))) if v == "A12B"));
+        assert!(matches!(Selector::parse("serial:\"A 1\""), Ok(Some(Selector::Serial(v))) if v == "A 1"));
+        assert!(matches!(Selector::parse("desc:\"CP2102 UART\""), Ok(Some(Selector::Desc(v))) if v == "cp2102 uart"));
+    }
+
+    #[test]
+    fn device_names_arent_selectors() {
+        assert!(matches!(Selector::parse("/dev/ttyUSB0"), Ok(None)));
+        assert!(matches!(Selector::parse("COM3"), Ok(None)));
+        assert!(matches!(Selector::parse("rfc:0403"), Ok(None)));
+    }
+
+    #[test]
+    fn selectors_match_usb_ports() {
+        let port = usb_port(0x0403, 0x6001, "A12B", "FTDI", "FT232R USB UART");
+        let picks = |spec: &str| Selector::parse(spec).unwrap().unwrap().matches(&port);
+        assert!(picks("usb:0403"));
+        assert!(picks("usb:0403:6001"));
+        assert!(!picks("usb:0403:6015"));
+        assert!(!picks("usb:10c4"));
+        assert!(picks("serial:A12B"));
+        assert!(!picks("serial:a12b"));
+        assert!(picks("desc:ftdi"));
+        assert!(picks("desc:usb uart"));
+        assert!(!picks("desc:cp210"));
+        let pci = SerialPortInfo { port_name: "/dev/ttyS0".to_string(), port_type: SerialPortType::PciPort };
+        assert!(!Selector::parse("desc:").unwrap().unwrap().matches(&pci));
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string(None), "null");
+        assert_eq!(json_string(Some("")), "\"\"");
+        assert_eq!(json_string(Some("FT232R")), "\"FT232R\"");
+        assert_eq!(json_string(Some("a \"b\" \\c")), "\"a \\\"b\\\" \\\\c\"");
+        assert_eq!(json_string(Some("\r\n\t\x01\x1f")), "\"\\r\\n\\t\\u0001\\u001f\"");
+        assert_eq!(json_string(Some("Café ✓")), "\"Café ✓\"");
+    }
+
+    #[test]
+    fn json_ports() {
+        let ports = [
+            usb_port(0x0403, 0x6001, "A12B", "FTDI", "FT232R"),
+            SerialPortInfo { port_name: "/dev/ttyS0".to_string(), port_type: SerialPortType::PciPort },
+        ];
+        assert_eq!(ports_json(&ports), concat!(
+            "[{\"name\":\"/dev/ttyUSB0\",\"type\":\"usb\",\"vid\":\"0403\",\"pid\":\"6001\",",
+            "\"manufacturer\":\"FTDI\",\"product\":\"FT232R\",\"serial_number\":\"A12B\"},",
+            "{\"name\":\"/dev/ttyS0\",\"type\":\"pci\",\"vid\":null,\"pid\":null,",
+            "\"manufacturer\":null,\"product\":null,\"serial_number\":null}]",
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+use std::io;
+use std::time::Duration;
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+
+/// The settings of a port, kept so they can be put back when it's reconnected.
+#[derive(Copy, Clone)]
+pub struct PortSettings {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub stop_bits: StopBits,
+    pub flow: FlowControl,
+    pub parity: Parity,
+    pub timeout: Duration,
+}
+
+impl PortSettings {
+    /// Reads the current settings of the port.
+    pub fn read(port: &dyn SerialPort) -> serialport::Result<PortSettings> {
+        Ok(PortSettings {
+            baud: port.baud_rate()?,
+            data_bits: port.data_bits()?,
+            stop_bits: port.stop_bits()?,
+            flow: port.flow_control()?,
+            parity: port.parity()?,
+            timeout: port.timeout(),
+        })
+    }
+}
+
+/// Stands in for a port that has been disconnected until it comes back. Reads get nothing, writes
+/// fail, and settings changed are kept so they're used when it's reopened.
+pub struct Disconnected {
+    /// The name of the device that was disconnected.
+    name: String,
+    /// The settings to reopen the port with.
+    settings: PortSettings,
+}
+
+impl Disconnected {
+    /// Creates a stand in for the named port, which had the given settings.
+    pub fn new(name: &str, settings: PortSettings) -> Disconnected {
+        Disconnected { name: name.to_string(), settings }
+    }
+}
+
+/// The error given for anything that needs the device.
+fn not_connected() -> serialport::Error {
+    serialport::Error::new(serialport::ErrorKind::NoDevice, "port is disconnected")
+}
+
+impl io::Read for Disconnected {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "port is disconnected"))
+    }
+}
+
+impl io::Write for Disconnected {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::NotConnected, "port is disconnected"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for Disconnected {
+    fn name(&self) -> Option<String> { Some(self.name.clone()) }
+    fn baud_rate(&self) -> serialport::Result<u32> { Ok(self.settings.baud) }
+    fn data_bits(&self) -> serialport::Result<DataBits> { Ok(self.settings.data_bits) }
+    fn flow_control(&self) -> serialport::Result<FlowControl> { Ok(self.settings.flow) }
+    fn parity(&self) -> serialport::Result<Parity> { Ok(self.settings.parity) }
+    fn stop_bits(&self) -> serialport::Result<StopBits> { Ok(self.settings.stop_bits) }
+    fn timeout(&self) -> Duration { self.settings.timeout }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.settings.baud = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.settings.data_bits = data_bits;
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.settings.flow = flow_control;
+        Ok(())
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.settings.parity = parity;
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.settings.stop_bits = stop_bits;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.settings.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> { Err(not_connected()) }
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> { Err(not_connected()) }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> { Err(not_connected()) }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> { Err(not_connected()) }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> { Err(not_connected()) }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> { Err(not_connected()) }
+    fn bytes_to_read(&self) -> serialport::Result<u32> { Ok(0) }
+    fn bytes_to_write(&self) -> serialport::Result<u32> { Ok(0) }
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> { Ok(()) }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> { Err(not_connected()) }
+    fn set_break(&self) -> serialport::Result<()> { Err(not_connected()) }
+    fn clear_break(&self) -> serialport::Result<()> { Err(not_connected()) }
+}
@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io;
+
+use serialport::SerialPort;
+
+use crate::reconnect::PortSettings;
+use crate::termios;
+
+
+/// The settings of a port from before nanocom opened it, put back on exit.
+pub struct Saved {
+    /// The baud rate, data bits, parity, stop bits and flow control.
+    settings: PortSettings,
+    /// Whether the DTR and RTS lines were up, `None` if they can't be read, on a pty or outside of
+    /// Linux for example.
+    lines: Option<(bool, bool)>,
+    /// The termios settings serialport doesn't cover, `None` if there's no raw file descriptor.
+    extras: Option<termios::Saved>,
+}
+
+impl Saved {
+
+    /// Puts back the saved settings and lines once anything still being sent has gone.
+    ///
+    /// * `fd` - The raw file descriptor of the port, if the platform has one.
+    pub fn restore(&self, port: &mut dyn SerialPort, fd: Option<i32>) -> io::Result<()> {
+        port.flush()?;
+        port.set_baud_rate(self.settings.baud)?;
+        port.set_data_bits(self.settings.data_bits)?;
+        port.set_parity(self.settings.parity)?;
+        port.set_stop_bits(self.settings.stop_bits)?;
+        port.set_flow_control(self.settings.flow)?;
+        if let (Some(fd), Some(extras)) = (fd, &self.extras) {
+            termios::restore(fd, extras)?;
+        }
+        if let Some((dtr, rts)) = self.lines {
+            port.write_data_terminal_ready(dtr)?;
+            port.write_request_to_send(rts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the port at `path` without changing anything and saves its settings.
+///
+/// The file returned, if any, should be kept open until the port has been opened again, closing
+/// the last handle to a port may hang up the line.
+#[cfg(unix)]
+pub fn save(path: &str) -> io::Result<(Option<File>, Saved)> {
+    use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: `into_raw_fd` hands over a duplicate of the file's descriptor that nothing else
+    // owns, so the port becomes its only owner and closes it when dropped, `file` keeps its own.
+    let mut port = unsafe { serialport::TTYPort::from_raw_fd(file.try_clone()?.into_raw_fd()) };
+    // Wrapping the handle claims the port, which would stop serialport opening it again
+    port.set_exclusive(false)?;
+    let saved = Saved {
+        settings: PortSettings::read(&port)?,
+        lines: termios::modem_lines(fd).ok(),
+        extras: termios::save(fd).ok(),
+    };
+    Ok((Some(file), saved))
+}
+
+/// Opens the port at `path` without changing anything and saves its settings.
+///
+/// The port is closed again before it's returned, as it can only be opened once at a time.
+#[cfg(windows)]
+pub fn save(path: &str) -> io::Result<(Option<File>, Saved)> {
+    use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+    let name = if path.starts_with('\\') { path.to_string() } else { format!(r"\\.\{}", path) };
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(name)?;
+    // SAFETY: `into_raw_handle` gives up the file's ownership of the handle, so the port becomes
+    // its only owner and closes it when dropped.
+    let port = unsafe { serialport::COMPort::from_raw_handle(file.into_raw_handle()) };
+    let saved = Saved {
+        settings: PortSettings::read(&port)?,
+        lines: None,
+        extras: None,
+    };
+    Ok((None, saved))
+}
+
+/// Settings can't be saved without a way of opening the port twice, so the port is left as it is
+/// on exit.
+#[cfg(not(any(unix, windows)))]
+pub fn save(_path: &str) -> io::Result<(Option<File>, Saved)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "saving port settings isn't supported on this platform"))
+}
@@ -1,18 +1,23 @@
-use std::io::ErrorKind;
+use std::io;
+use std::time::Duration;
 
 
-use crate::{state::State, key::KeyIn, key::EscapeSequence};
+use crate::{state::State, key::KeyIn, terminal};
+use crate::args::{DisplayMode, Map};
+use crate::transfer::zmodem::ZRQINIT_SEQ;
 
 
-/// Polls the serial port for any data, parsing any escape sequences. 
+/// Polls the serial port for any data, parsing any escape sequences unless showing a hex dump. 
+/// 
+/// Escape sequences are parsed a byte at a time by [Parser](crate::parser::Parser), giving 
+/// `KeyIn::Nothing` until a sequence is finished. 
 pub fn poll_port_parse_data(state: &mut State) -> Result<KeyIn, ()> {
     let v = match try_get_char(state)? {
         Some(c) => c,
         None => return Ok(KeyIn::Nothing)
     };
-    let res = if v == 0x1B {
-        let res = handle_escape(state)?;
-        KeyIn::Escape(res)
+    let res = if state.display == DisplayMode::Text && !state.imap.contains(&Map::SpcHex) {
+        state.parser.advance(v)
     } else {
         KeyIn::Char(v)
     };
@@ -20,123 +25,62 @@ pub fn poll_port_parse_data(state: &mut State) -> Result<KeyIn, ()> {
     Ok(res)
 }
 
-/// Polls the serial port for a byte, displays an error message and throws if error with reading. 
-fn try_get_char(state: &mut State) -> Result<Option<u8>, ()> {
-    let mut buf = [0u8];
-    match state.port.read(&mut buf) {
-        Ok(0) => Ok(None),
-        Ok(1..) => Ok(Some(buf[0])),
-        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
-        Err(e) if e.kind() == ErrorKind::TimedOut => Ok(None),
-        Err(e) => {
-            println!("\r\n*** Failed to read from port, exiting \r\n{} ", e);
-            Err(())
-        }
+/// Shows the bytes of an escape sequence or character that was left unfinished for too long, so a 
+/// lone ESC or a sequence cut short isn't held back forever. 
+pub fn flush_stale_sequence(state: &mut State) {
+    for v in state.parser.flush_stale() {
+        let _ = terminal::print_char(v, state);
     }
+    let _ = terminal::flush_stale_char(state);
 }
 
-/// Blocking polls the serial port for a byte. 
-fn get_char(state: &mut State) -> Result<u8, ()> {
-    loop {
-        match try_get_char(state)? {
-            Some(v) => return Ok(v),
-            None => continue
-        }
+/// How long until an unfinished escape sequence or character times out, `None` if there isn't one. 
+pub fn time_left(state: &State) -> Option<Duration> {
+    match (state.parser.time_left(), state.utf8.time_left()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b)
     }
 }
 
-/// Dispatches the correct escape sequence parser for the type of escape sequence. 
-/// 
-/// If a `0x1B` byte is received from the serial port, this signifies the start of an escape sequence, 
-/// the next byte will determine the type; and thus how we parse/how the sequence is terminated. 
-/// 
-/// If byte 2 is:
-/// * `0x20..=0c2F` - this is an NF sequence and is terminated by a byte between `0x30..=0x7E`
-/// * `=0x5B` - this signals a CSI sequence and is terminated by a byte between `0x40..=0x7E`
-/// * `=0x5D` - this signals an OS command sequence and is terminated by either `0x07`, `0x9C`, `0x1B,0x5C`
-/// * `0x30..=0x7E` - this is a 2 byte command and can be terminated right there 
-/// 
-/// Anything else is an invalid sequence and will yield `EscapeSequence::Invalid`.
-/// 
-pub fn handle_escape(state: &mut State) -> Result<EscapeSequence, ()> {
-    let seq = vec![0x1B];
-    let v = get_char(state)?;
-    match v {
-        0x20..=0x2F => handle_nf(v, seq, state),
-        0x5B => handle_csi(v, seq, state),
-        0x5D => handle_os(v, seq, state),
-        0x30..=0x7E if v != 0x5B || v != 0x5D => handle_single(v, seq),
-        _ => Ok(EscapeSequence::Invalid)
-    }
-}
-
-/// Parses a single byte escape sequence. 
-pub fn handle_single(byte: u8, seq: Vec<u8>) -> Result<EscapeSequence, ()> {
-    let mut seq = seq;
-    seq.push(byte);
-    return Ok(EscapeSequence::UnknownSeq(seq))
-}
-
-/// Parses an [OS command](https://en.wikipedia.org/wiki/ANSI_escape_code#Operating_System_Command_sequences) escape sequence. 
-pub fn handle_os(byte: u8, seq: Vec<u8>, state: &mut State) -> Result<EscapeSequence, ()> {
-    let mut seq = seq;
-    seq.push(byte);
-    loop {
-        let v = get_char(state)?;
-        seq.push(v);
-        match seq[..seq.len()] {
-            [.., 0x07] | [.., 0x9C] | [0x1B, 0x5C] => 
-                return Ok(EscapeSequence::UnknownSeq(seq)),
-            _ => continue
-        }
-    }
+/// Polls the serial stream for a byte, see [got_char]. 
+fn try_get_char(state: &mut State) -> Result<Option<u8>, ()> {
+    let res = state.serial.get_byte();
+    got_char(res, state)
 }
 
-/// Handles [Control Sequence Introducer](https://en.wikipedia.org/wiki/ANSI_escape_code#Control_Sequence_Introducer_commands) sequences 
-pub fn handle_csi(byte: u8, seq: Vec<u8>, state: &mut State) -> Result<EscapeSequence, ()> {
-    let mut seq = seq;
-    seq.push(byte);
-    loop {
-        let v = get_char(state)?;
-        seq.push(v);
-        if let Some(0x40..=0x7E) = seq.last() { 
-            return Ok(match_csi(seq))
+/// Handles a byte read from the serial stream, displays an error message and throws if error with 
+/// reading, unless reconnecting, when the port is swapped for a stand in until it comes back. 
+fn got_char(res: io::Result<Option<u8>>, state: &mut State) -> Result<Option<u8>, ()> {
+    match res {
+        Ok(Some(v)) => {
+            state.log_received(v);
+            detect_zmodem(v, state);
+            Ok(Some(v))
+        },
+        Ok(None) => Ok(None),
+        Err(e) if state.reconnect => {
+            if state.disconnect(&e) { Ok(None) } else { Err(()) }
+        },
+        Err(e) => {
+            println!("\r\n*** Failed to read from port, exiting \r\n{} ", e);
+            Err(())
         }
     }
 }
 
-/// Returns the correct [EscapeSequence] option for a given CSI escape sequence. 
-pub fn match_csi(seq: Vec<u8>) -> EscapeSequence {
-    let len = seq.len();
-    if len != 3 && len != 4 {
-        return EscapeSequence::End;
-    }
-    match seq[..seq.len()] {
-        [0x1B, 0x5B, 0x44] => EscapeSequence::ArrowLeft,
-        [0x1B, 0x5B, 0x43] => EscapeSequence::ArrowRight,
-        [0x1B, 0x5B, 0x41] => EscapeSequence::ArrowUp,
-        [0x1B, 0x5B, 0x42] => EscapeSequence::ArrowDown,
-        [0x1B, 0x5B, 0x5A] => EscapeSequence::BackTab,
-        [0x1B, 0x5B, 0x31, 0x7E] => EscapeSequence::Home,
-        [0x1B, 0x5B, 0x34, 0x7E] => EscapeSequence::End,
-        [0x1B, 0x5B, 0x33, 0x7E] => EscapeSequence::Del,
-        [0x1B, 0x5B, 0x32, 0x7E] => EscapeSequence::Insert,
-        [0x1B, 0x5B, 0x35, 0x7E] => EscapeSequence::PageUp,
-        [0x1B, 0x5B, 0x36, 0x7E] => EscapeSequence::PageDown,
-        _ => EscapeSequence::UnknownSeq(seq)
-    }
-}
-
-/// Handles [nF](https://en.wikipedia.org/wiki/ANSI_escape_code#nF_Escape_sequences) escape sequences. 
-pub fn handle_nf(byte: u8, seq: Vec<u8>, state: &mut State) -> Result<EscapeSequence, ()> {
-    let mut seq = seq;
-    seq.push(byte);
-    loop {
-        let v = get_char(state)?;
-        seq.push(v);
-        if let Some(0x30..=0x7E) = seq.last() {
-            return Ok(EscapeSequence::UnknownSeq(seq))
-        }
+/// Watches the data received for the start of a ZMODEM send (a ZRQINIT header), flagging in the 
+/// state that a receive should be started once it's seen. 
+fn detect_zmodem(byte: u8, state: &mut State) {
+    let matched = state.zmodem_match;
+    state.zmodem_match = if byte == ZRQINIT_SEQ[matched] {
+        matched + 1
+    } else if byte == ZRQINIT_SEQ[0] {
+        if matched == 2 { 2 } else { 1 }
+    } else {
+        0
+    };
+    if state.zmodem_match == ZRQINIT_SEQ.len() {
+        state.zmodem_match = 0;
+        state.zmodem_autostart = true;
     }
 }
-
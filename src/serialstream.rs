@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::thread::{self, JoinHandle};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+
+/// How long a single read by the reader thread may block before checking if it should stop.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The most read from the port at once.
+const READ_SIZE: usize = 4096;
+
+/// Struct to hold the handle for the serial port reading routine and the receiver from that
+/// routine, along with anything received but not yet used.
+pub struct SerialStream {
+    _handle: Option<JoinHandle<()>>,
+    data_recv: Receiver<io::Result<Vec<u8>>>,
+    buffer: VecDeque<u8>,
+    error: Option<io::Error>,
+    _is_connected_sender: Sender<()>
+}
+
+impl SerialStream {
+
+    /// Starts a new serial stream service, reading from the port in its own thread until the
+    /// port fails or the stream is dropped.
+    ///
+    /// * `port` - A clone of the serial port to read from.
+    /// * `waker` - Sent to every time something is received, so the main loop can wait on it.
+    pub fn new(mut port: Box<dyn SerialPort>, waker: Sender<()>) -> SerialStream {
+        let (data_sender, data_recv) = mpsc::channel::<io::Result<Vec<u8>>>();
+        let (is_connected_sender, is_connected_rev) = mpsc::channel::<()>();
+        let _ = port.set_timeout(READ_TIMEOUT);
+
+        let handle = thread::spawn(move ||
+            serial_stream_loop(port, data_sender, waker, is_connected_rev));
+
+        SerialStream {
+            _handle: Some(handle),
+            data_recv,
+            buffer: VecDeque::new(),
+            error: None,
+            _is_connected_sender: is_connected_sender
+        }
+    }
+
+    /// Creates a stream that never receives anything, for a port that can't be read from.
+    pub fn closed() -> SerialStream {
+        let (_, data_recv) = mpsc::channel::<io::Result<Vec<u8>>>();
+        let (is_connected_sender, _) = mpsc::channel::<()>();
+        SerialStream { _handle: None, data_recv, buffer: VecDeque::new(), error: None, _is_connected_sender: is_connected_sender }
+    }
+
+    /// Non-blocking gets the next byte received, if any.
+    pub fn get_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.buffer.is_empty() && let Ok(v) = self.data_recv.try_recv() {
+            self.receive(v);
+        }
+        self.take()
+    }
+
+    /// Gets the next byte received, waiting for up to `timeout` for one or forever if `None`,
+    /// returns `None` if nothing arrived or the stream has stopped.
+    pub fn wait_byte(&mut self, timeout: Option<Duration>) -> io::Result<Option<u8>> {
+        if self.buffer.is_empty() && self.error.is_none() {
+            let res = match timeout {
+                Some(t) => self.data_recv.recv_timeout(t).ok(),
+                None => self.data_recv.recv().ok()
+            };
+            if let Some(v) = res { self.receive(v); }
+        }
+        self.take()
+    }
+
+    /// Returns true if there is received data waiting to be used, or an error waiting to be seen.
+    pub fn has_data(&mut self) -> bool {
+        if self.buffer.is_empty() && self.error.is_none() && let Ok(v) = self.data_recv.try_recv() {
+            self.receive(v);
+        }
+        !self.buffer.is_empty() || self.error.is_some()
+    }
+
+    /// Discards anything received but not yet used.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        while let Ok(v) = self.data_recv.try_recv() {
+            if let Err(e) = v { self.error = Some(e); }
+        }
+    }
+
+    /// Adds what the reader thread sent to the buffer, or keeps the error it sent.
+    fn receive(&mut self, res: io::Result<Vec<u8>>) {
+        match res {
+            Ok(data) => self.buffer.extend(data),
+            Err(e) => self.error = Some(e)
+        }
+    }
+
+    /// Takes the next byte from the buffer, or the error once the buffer is empty.
+    fn take(&mut self) -> io::Result<Option<u8>> {
+        if let Some(v) = self.buffer.pop_front() { return Ok(Some(v)) }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(None)
+        }
+    }
+}
+
+/// Main loop which reads from the serial port and sends anything received to a channel, waking the
+/// main loop. Sends the error and stops if the port fails.
+fn serial_stream_loop(mut port: Box<dyn SerialPort>,
+    data_sender: Sender<io::Result<Vec<u8>>>,
+    waker: Sender<()>,
+    is_connected_rev: Receiver<()>)
+{
+    let mut buf = [0u8; READ_SIZE];
+    loop {
+        if let Err(TryRecvError::Disconnected) = is_connected_rev.try_recv() { return };
+        let res = match port.read(&mut buf) {
+            Ok(0) => continue,
+            Ok(n) => Ok(buf[..n].to_vec()),
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => continue,
+            Err(e) => Err(e)
+        };
+        let failed = res.is_err();
+        if data_sender.send(res).is_err() { return };
+        let _ = waker.send(());
+        if failed { return }
+    }
+}
@@ -1,8 +1,40 @@
-use console::Term;
-use serialport::{DataBits, FlowControl, SerialPort};
+use std::path::PathBuf;
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use console::{Style, Term};
+use regex::bytes::Regex;
+use serialport::{DataBits, FlowControl, SerialPort, StopBits, UsbPortInfo};
 
 use super::utils::get_ascii_byte;
-use super::args::Args;
+use super::args::{Args, Charset, DisplayMode, Eol, InvalidUtf8, KeyEncoding, LogMode, Map, Parity, Protocol, Timestamp};
+use super::logfile::LogFile;
+use super::terminal::timestamp_prefix;
+use super::termios;
+use super::saved::{self, Saved};
+use super::ports;
+use super::reconnect::{Disconnected, PortSettings};
+use super::serialstream::SerialStream;
+use super::parser::Parser;
+use super::utf8::Utf8Decoder;
+use super::escape_handlers::{TabStops, TextStyle};
+use super::key::GraphicSet;
+
+
+/// How often a disconnected port is looked for. 
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the port is tried while waiting for it to show up at startup. 
+const WAIT_INTERVAL: Duration = Duration::from_millis(200);
+/// The frames of the spinner shown while waiting for the port. 
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+/// The exit code when the port didn't show up before the `--wait` timeout ran out. 
+pub const WAIT_TIMED_OUT: i32 = 3;
+/// The exit code when the port selector given is invalid or matches more than one port. 
+pub const BAD_SELECTOR: i32 = 1;
 
 
 /// Model containing all the settings and mutable aspects of the program state. 
@@ -23,14 +55,124 @@ pub struct State {
     /// if it's unsupported by the serial device, this is mainly for tracking cycling through 
     /// options. 
     pub flow: FlowControl,
+    /// The current selected parity, tracked here as mark and space parity can't be read back 
+    /// through serialport. 
+    pub parity: Parity,
     /// Is program in command mode. 
     pub command_mode: bool,
     /// Local echo (send characters to terminal as they're typed).
     pub local_echo: bool,
+    /// The protocol used by the file send and receive commands. 
+    pub protocol: Protocol,
+    /// The directory files received with a batch protocol are saved in to. 
+    pub receive_dir: PathBuf,
+    /// Resume partially transferred files with ZMODEM. 
+    pub resume: bool,
+    /// How much of the start of a ZMODEM send has been seen in the data received. 
+    pub zmodem_match: usize,
+    /// The start of a ZMODEM send has been seen and a receive should be started. 
+    pub zmodem_autostart: bool,
+    /// The delay after each character sent by the ASCII upload command. 
+    pub char_delay: Duration,
+    /// The delay after each line sent by the ASCII upload command. 
+    pub line_delay: Duration,
+    /// The line ending translation done by the ASCII upload command. 
+    pub eol: Eol,
+    /// The prompt the ASCII upload command waits for before sending the next line, if any. 
+    pub prompt: Option<Regex>,
+    /// The session log, if logging is running. 
+    pub log: Option<LogFile>,
+    /// The path of the session log, kept when logging is stopped so it can be started again. 
+    pub log_path: Option<PathBuf>,
+    /// Whether the session log is added to or overwritten when opened. 
+    pub log_mode: LogMode,
+    /// Also write bytes sent to the session log. 
+    pub log_sent: bool,
+    /// What each line received is prefixed with. 
+    pub timestamp: Timestamp,
+    /// The strftime format of the time of day timestamps. 
+    pub timestamp_format: String,
+    /// When the session started, for timestamps relative to it. 
+    pub session_start: Instant,
+    /// The next character printed starts a new line. 
+    pub line_start: bool,
+    /// When the previous line printed started, for timestamps relative to it. 
+    pub last_line: Instant,
+    /// The next byte received written to the log starts a new line. 
+    pub log_line_start: bool,
+    /// When the previous line written to the log started, for timestamps relative to it. 
+    pub log_last_line: Instant,
+    /// How data received is shown. 
+    pub display: DisplayMode,
+    /// The offset of the start of the current row of the hex dump. 
+    pub hex_offset: u64,
+    /// The bytes in the current row of the hex dump. 
+    pub hex_row: Vec<u8>,
+    /// Splits the data received in to characters and escape sequences. 
+    pub parser: Parser,
+    /// Decodes the text received as UTF-8. 
+    pub utf8: Utf8Decoder,
+    /// The character set of the text received. 
+    pub charset: Charset,
+    /// The graphic sets designated as G0 and G1 by the device. 
+    pub graphic_sets: [GraphicSet; 2],
+    /// The device has sent SO, so text is shown in G1 until it sends SI. 
+    pub shift_out: bool,
+    /// How bytes received that aren't valid UTF-8 are shown. 
+    pub invalid_utf8: InvalidUtf8,
+    /// How non-ASCII characters typed are encoded before they're sent. 
+    pub key_encoding: KeyEncoding,
+    /// The colors and attributes set by the device for the text it sends. 
+    pub text_style: TextStyle,
+    /// The [Style] the text received is printed with, made from `text_style`. 
+    pub style: Style,
+    /// The text attributes saved along with the cursor position by DECSC. 
+    pub saved_style: TextStyle,
+    /// The device has set a scroll region, which is reset on exit. 
+    pub scroll_region: bool,
+    /// The number of columns between the tab stops the terminal starts with. 
+    pub tab_width: u16,
+    /// The columns tabs received stop at. 
+    pub tab_stops: TabStops,
+    /// The 0 based column the cursor is in, followed so tabs can be moved from `tab_stops`. 
+    pub column: u16,
+    /// The column saved along with the cursor position by DECSC. 
+    pub saved_column: u16,
+    /// The mappings applied to data received before it is shown. 
+    pub imap: Vec<Map>,
+    /// The mappings applied to keys typed before they are sent. 
+    pub omap: Vec<Map>,
+    /// The mappings applied to keys typed before they are shown by local echo. 
+    pub emap: Vec<Map>,
     /// Name of the port on the OS.
     pub port_name: String,
     /// The serial port wrapper. 
     pub port: Box<dyn SerialPort>,
+    /// The raw file descriptor of the serial port, for settings serialport doesn't cover, `None` 
+    /// on platforms without one. 
+    pub port_fd: Option<i32>,
+    /// The port settings from before nanocom opened it, restored on exit unless noreset is set, 
+    /// `None` if they couldn't be saved. 
+    pub saved: Option<Saved>,
+    /// The data received from the serial port, read in its own thread. 
+    pub serial: SerialStream,
+    /// Sent to whenever a key or data is received, passed to the threads reading them. 
+    pub waker: Sender<()>,
+    /// Woken whenever a key or data is received, the main loop waits on this. 
+    pub wake: Receiver<()>,
+    /// Keep running if the port disconnects and reopen it when it comes back. 
+    pub reconnect: bool,
+    /// When the port was last looked for since it disconnected, `None` while it's connected. 
+    pub disconnected: Option<Instant>,
+    /// The last known settings of the port, to reopen it with if it disconnects. 
+    pub settings: Option<PortSettings>,
+    /// The USB identity of the port, to find it again if it comes back under another name. 
+    pub usb: Option<UsbPortInfo>,
+    /// Sent to the device when it sends ENQ. 
+    pub answerback: String,
+    /// How many cursor position reports have been asked of the terminal and not yet answered, 
+    /// shared with the input stream which picks the answers out of the keys. 
+    pub cursor_reports: Arc<AtomicUsize>,
     /// Terminal interface wrapper. 
     pub term: Term
 }
@@ -39,28 +181,266 @@ impl State {
 
     /// Generates a new state from the given start arguments. 
     pub fn new_from_args(args: &Args) -> Result<State, ()> {
-        let port = match get_serial_port(&args) {
+        let opened = match get_serial_port(&args) {
             Ok(v) => v,
             Err(_) => return Err(())
         };
+        let prompt = match args.prompt.as_deref().map(Regex::new).transpose() {
+            Ok(v) => v,
+            Err(e) => {
+                println!("*** Invalid prompt regex, reason: \r\n{}", e);
+                return Err(());
+            }
+        };
+        let mut test = String::new();
+        if write!(test, "{}", chrono::Local::now().format(&args.timestamp_format)).is_err() {
+            println!("*** Invalid timestamp format {}", args.timestamp_format);
+            return Err(());
+        }
+        let log = match &args.logfile {
+            Some(path) => match LogFile::open(path, args.log_mode) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    println!("*** Failed to open log file, reason: \r\n{}", e);
+                    return Err(());
+                }
+            },
+            None => None
+        };
+        let (waker, wake) = mpsc::channel();
+        let serial = match opened.port.try_clone() {
+            Ok(v) => SerialStream::new(v, waker.clone()),
+            Err(e) => {
+                println!("*** Failed to start reading from serial port, reason: \r\n{}", e.description);
+                return Err(());
+            }
+        };
+        let (dtr, rts) = opened.fd.and_then(|fd| termios::modem_lines(fd).ok())
+            .unwrap_or((!args.noinit, false));
+        let usb = if args.reconnect { ports::usb_identity(&opened.name) } else { None };
         let escape_code = get_ascii_byte(args.escape.to_ascii_lowercase()) - 96;
         let term = Term::stdout();
 
 
-        Ok(State {
+        let mut state = State {
             escape: args.escape,
             escape_code,
             noinit: args.noinit,
             noreset: args.noreset,
-            dtr: false,
-            rts: false,
+            dtr,
+            rts,
             flow: args.flow.to_serialport(),
+            parity: args.parity,
             command_mode: false,
             local_echo: false,
-            port_name: args.port.clone(),
-            port: port,
+            protocol: args.protocol,
+            receive_dir: args.receive_dir.clone(),
+            resume: args.resume,
+            zmodem_match: 0,
+            zmodem_autostart: false,
+            char_delay: Duration::from_millis(args.char_delay),
+            line_delay: Duration::from_millis(args.line_delay),
+            eol: args.eol,
+            prompt,
+            log,
+            log_path: args.logfile.clone(),
+            log_mode: args.log_mode,
+            log_sent: args.log_sent,
+            timestamp: args.timestamp,
+            timestamp_format: args.timestamp_format.clone(),
+            session_start: Instant::now(),
+            line_start: true,
+            last_line: Instant::now(),
+            log_line_start: true,
+            log_last_line: Instant::now(),
+            display: args.display_mode(),
+            hex_offset: 0,
+            hex_row: Vec::with_capacity(16),
+            parser: Parser::new(),
+            utf8: Utf8Decoder::new(),
+            charset: args.charset,
+            graphic_sets: [GraphicSet::Ascii; 2],
+            shift_out: false,
+            invalid_utf8: args.invalid_utf8,
+            key_encoding: args.key_encoding,
+            text_style: TextStyle::default(),
+            style: Style::new(),
+            saved_style: TextStyle::default(),
+            scroll_region: false,
+            tab_width: args.tab_width,
+            tab_stops: TabStops::new(args.tab_width),
+            column: 0,
+            saved_column: 0,
+            imap: args.imap.clone(),
+            omap: args.omap.clone(),
+            emap: args.emap.clone(),
+            port_name: opened.name,
+            port: opened.port,
+            port_fd: opened.fd,
+            saved: opened.saved,
+            serial,
+            waker,
+            wake,
+            reconnect: args.reconnect,
+            disconnected: None,
+            settings: None,
+            usb,
+            answerback: args.answerback.clone(),
+            cursor_reports: Arc::new(AtomicUsize::new(0)),
             term
-        })
+        };
+        if !args.noinit && args.parity.is_mark_space() && let Err(e) = state.set_parity(args.parity) {
+            println!("*** Failed to set {} parity, reason: \r\n{}", args.parity.show(), e);
+            return Err(());
+        }
+        state.remember_settings();
+        Ok(state)
+    }
+
+    /// Sets the parity of the serial port, mark and space parity are set with termios as 
+    /// serialport doesn't support them. 
+    pub fn set_parity(&mut self, parity: Parity) -> io::Result<()> {
+        if parity.is_mark_space() {
+            let fd = self.port_fd.ok_or(io::Error::new(io::ErrorKind::Unsupported, 
+                "mark and space parity need a termios port"))?;
+            termios::set_mark_space(fd, parity == Parity::M)?;
+        } else {
+            if let Some(fd) = self.port_fd { termios::clear_mark_space(fd)?; }
+            self.port.set_parity(parity.to_serialport())?;
+        }
+        self.parity = parity;
+        Ok(())
+    }
+
+    /// Gets the port ready to be closed: if noreset is set the lines are left as they are so a 
+    /// connected modem isn't hung up, otherwise the settings the port had before nanocom opened 
+    /// it are put back. 
+    pub fn reset_port(&mut self) {
+        let res = match (&self.saved, self.noreset) {
+            (_, true) => self.port_fd.map_or(Ok(()), termios::keep_lines),
+            (Some(saved), false) => saved.restore(self.port.as_mut(), self.port_fd),
+            (None, false) => Ok(())
+        };
+        if let Err(e) = res {
+            println!("*** Failed to reset serial port, reason: \r\n{}", e);
+        }
+    }
+
+    /// Remembers the current settings of the port when reconnecting, so it can be reopened with 
+    /// them if it disconnects. 
+    pub fn remember_settings(&mut self) {
+        if !self.reconnect { return }
+        if let Ok(v) = PortSettings::read(self.port.as_ref()) {
+            self.settings = Some(v);
+        }
+    }
+
+    /// Swaps the port for a stand in after it fails, closing it so the device can come back under 
+    /// the same name, returning false if its settings were never known so it can't be reopened. 
+    pub fn disconnect(&mut self, e: &io::Error) -> bool {
+        let settings = match self.settings {
+            Some(v) => v,
+            None => {
+                println!("\r\n*** Port {} disconnected and can't be reopened, exiting \r\n{} ", self.port_name, e);
+                return false;
+            }
+        };
+        println!("\r\n*** Port {} disconnected, waiting for it to come back, reason: \r\n{} \r\n", self.port_name, e);
+        self.port = Box::new(Disconnected::new(&self.port_name, settings));
+        self.port_fd = None;
+        self.serial = SerialStream::closed();
+        self.disconnected = Some(Instant::now());
+        true
+    }
+
+    /// Looks for the port again after it disconnected, at most every [RECONNECT_INTERVAL], 
+    /// reopening it with the settings, DTR and RTS it had if it's found. 
+    pub fn try_reconnect(&mut self) {
+        match self.disconnected {
+            Some(v) if v.elapsed() >= RECONNECT_INTERVAL => (),
+            _ => return
+        }
+        self.disconnected = Some(Instant::now());
+        let name = match ports::find_again(&self.port_name, self.usb.as_ref()) {
+            Some(v) => v,
+            None => return
+        };
+        let settings = match PortSettings::read(self.port.as_ref()) {
+            Ok(v) => v,
+            Err(_) => return
+        };
+        let builder = serialport::new(&name, settings.baud)
+            .data_bits(settings.data_bits)
+            .stop_bits(settings.stop_bits)
+            .flow_control(settings.flow)
+            .parity(settings.parity)
+            .timeout(settings.timeout)
+            .dtr_on_open(self.dtr);
+        let (port, fd) = match open_port(builder) {
+            Ok(v) => v,
+            Err(_) => return
+        };
+        let reader = match port.try_clone() {
+            Ok(v) => v,
+            Err(_) => return
+        };
+        self.serial = SerialStream::new(reader, self.waker.clone());
+        self.port = port;
+        self.port_fd = fd;
+        self.port_name = name;
+        self.disconnected = None;
+        if self.parity.is_mark_space() {
+            let _ = self.set_parity(self.parity);
+        }
+        let _ = self.port.write_request_to_send(self.rts);
+        println!("\r\n*** Port {} reconnected \r\n", self.port_name);
+    }
+
+    /// Sends a reply to a query from the device, logging it if sent data is logged. Errors are left 
+    /// to be picked up by the next read. 
+    pub fn reply(&mut self, data: &[u8]) {
+        if data.is_empty() { return }
+        if self.port.write_all(data).is_ok() && self.log_sent {
+            self.log(data);
+        }
+    }
+
+    /// Writes bytes sent or received to the session log if it's running, stopping logging with 
+    /// an error message if the log can't be written to. 
+    pub fn log(&mut self, data: &[u8]) {
+        let log = match self.log.as_mut() {
+            Some(v) => v,
+            None => return
+        };
+        if let Err(e) = log.write(data) {
+            println!("\r\n*** Failed to write to log file, logging stopped, reason: \r\n{} \r\n", e);
+            self.log = None;
+        }
+    }
+
+    /// Writes anything buffered for the session log out to the file, stopping logging with an error 
+    /// message if it can't be written to. 
+    pub fn flush_log(&mut self) {
+        let log = match self.log.as_mut() {
+            Some(v) => v,
+            None => return
+        };
+        if let Err(e) = log.flush() {
+            println!("\r\n*** Failed to write to log file, logging stopped, reason: \r\n{} \r\n", e);
+            self.log = None;
+        }
+    }
+
+    /// Writes a byte received to the session log if it's running, prefixed with a timestamp if 
+    /// it starts a new line and timestamps are on. 
+    pub fn log_received(&mut self, byte: u8) {
+        if self.log.is_none() { return }
+        if self.log_line_start && self.timestamp != Timestamp::Off {
+            let prefix = timestamp_prefix(self.timestamp, &self.timestamp_format, self.session_start, &mut self.log_last_line);
+            self.log(prefix.as_bytes());
+        }
+        self.log_line_start = byte == b'\n';
+        self.log(&[byte]);
     }
 
     /// Generates a human readable message string of all the configurable port settings. 
@@ -75,7 +455,7 @@ impl State {
             ",
             self.port.baud_rate()?,
             self.port.flow_control()?,
-            self.port.parity()?,
+            self.parity.show(),
             self.port.data_bits()?,
             self.port.stop_bits()?,
             if self.dtr { "up" } else { "down" },
@@ -85,31 +465,113 @@ impl State {
     }
 }
 
-/// Tries to configure and open a serial port based on the passed settings. 
-fn get_serial_port(args: &Args) -> Result<Box<dyn SerialPort>, ()> {
+/// Tries to configure and open a serial port based on the passed settings, retrying until it shows 
+/// up or the timeout runs out if `--wait` is given, exiting with [WAIT_TIMED_OUT] if it doesn't, 
+/// or with [BAD_SELECTOR] if the port selector can't be resolved. 
+fn get_serial_port(args: &Args) -> Result<OpenedPort, ()> {
     let databits = match args.databits {
         5 => DataBits::Five,
         6 => DataBits::Six,
         7 => DataBits::Seven,
         _ => DataBits::Eight,
     };
+    let stopbits = match args.stopbits {
+        2 => StopBits::Two,
+        _ => StopBits::One,
+    };
+
+    let started = Instant::now();
+    let mut tick = 0;
+    loop {
+        let reason = match open_serial_port(args, databits, stopbits) {
+            Ok(v) => {
+                if tick > 0 { print!("\r\x1b[2K"); }
+                return Ok(v);
+            },
+            Err(OpenError::Failed(v)) => {
+                if tick > 0 { print!("\r\x1b[2K"); }
+                println!("*** {}", v);
+                std::process::exit(BAD_SELECTOR);
+            },
+            Err(OpenError::Missing(v)) => v
+        };
+        let timeout = match args.wait {
+            Some(v) => v.map(Duration::from_secs),
+            None => {
+                println!("*** {}", reason);
+                return Err(());
+            }
+        };
+        if timeout.is_some_and(|v| started.elapsed() >= v) {
+            println!("\r\x1b[2K*** Timed out waiting for {} \r\n*** {}", args.port_name(), reason);
+            std::process::exit(WAIT_TIMED_OUT);
+        }
+        print!("\r*** Waiting for {} {} ", args.port_name(), SPINNER[tick % SPINNER.len()]);
+        let _ = io::Write::flush(&mut io::stdout());
+        tick += 1;
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
 
+/// Why [open_serial_port] couldn't open the port. 
+enum OpenError {
+    /// The port isn't there or couldn't be opened, it may be worth trying again. 
+    Missing(String),
+    /// The port selector given can't be resolved, with the reason why. 
+    Failed(String),
+}
+
+/// Resolves the port selector given and tries to open the port once, saving its settings before 
+/// they're changed. 
+fn open_serial_port(args: &Args, databits: DataBits, stopbits: StopBits) -> Result<OpenedPort, OpenError> {
+    let name = match ports::resolve_port(args.port_name()) {
+        Ok(Some(v)) => v,
+        Ok(None) => return Err(OpenError::Missing(format!("No serial port matches {}", args.port_name()))),
+        Err(e) => return Err(OpenError::Failed(e.to_string()))
+    };
     let port_builder = if args.noinit { 
-        serialport::new(&args.port.clone(), args.baud)
+        serialport::new(&name, args.baud)
             .preserve_dtr_on_open()
     } else {
-        serialport::new(&args.port.clone(), args.baud)
+        serialport::new(&name, args.baud)
             .flow_control(args.flow.to_serialport())
             .parity(args.parity.to_serialport())
             .dtr_on_open(true)
             .data_bits(databits)
+            .stop_bits(stopbits)
     };
 
-    match port_builder.open() {
-        Ok(p) => Ok(p),
-        Err(e) => { 
-            println!("*** Failed to open serial port, reason: \r\n{}", e.description);
-            return Err(());
-        }
+    // Kept open until the port has been opened so the line isn't hung up in between. 
+    let saved = saved::save(&name).ok();
+    match open_port(port_builder) {
+        Ok((port, fd)) => Ok(OpenedPort { name, port, fd, saved: saved.map(|(_, saved)| saved) }),
+        Err(e) => Err(OpenError::Missing(format!("Failed to open serial port, reason: \r\n{}", e.description)))
     }
 }
+
+/// A serial port opened by [get_serial_port]. 
+struct OpenedPort {
+    /// The name of the device opened, the port selector given resolved. 
+    name: String,
+    /// The serial port wrapper. 
+    port: Box<dyn SerialPort>,
+    /// The raw file descriptor of the serial port, if the platform has one. 
+    fd: Option<i32>,
+    /// The settings of the port from before it was opened, if they could be saved. 
+    saved: Option<Saved>,
+}
+
+/// Opens the port, keeping hold of the raw file descriptor. 
+#[cfg(unix)]
+fn open_port(builder: serialport::SerialPortBuilder) -> serialport::Result<(Box<dyn SerialPort>, Option<i32>)> {
+    use std::os::fd::AsRawFd;
+    let port = builder.open_native()?;
+    let fd = port.as_raw_fd();
+    Ok((Box::new(port), Some(fd)))
+}
+
+/// Opens the port, there's no raw file descriptor outside of unix. 
+#[cfg(not(unix))]
+fn open_port(builder: serialport::SerialPortBuilder) -> serialport::Result<(Box<dyn SerialPort>, Option<i32>)> {
+    Ok((builder.open()?, None))
+}
@@ -1,39 +1,196 @@
 use std::io::{self, Write};
+use std::time::Instant;
 
+use crate::args::{Charset, DisplayMode, InvalidUtf8, Timestamp};
 use crate::state::State;
 use crate::utils::put_char;
 use crate::utils::put_string;
+use crate::utils::put_bytes;
 use crate::key::KeyIn;
 use crate::escape_handlers;
-use crate::utils::TABS;
+use crate::mapping::map_byte;
+use crate::utf8::Decoded;
+use crate::charset;
 
 
 /// Dispatches the correct routine for printing data received from the serial port. 
 pub fn print_data_in(data: KeyIn, state: &mut State) -> Result<(), io::Error> {
+    if state.display == DisplayMode::Hex {
+        data.to_bytes().into_iter().for_each(|b| print_hex(b, state));
+        return Ok(());
+    }
+    if state.display == DisplayMode::Raw {
+        put_bytes(&data.to_bytes());
+        return Ok(());
+    }
     match data {
         KeyIn::Nothing => return Ok(()),
         KeyIn::Char(c) => print_char(c, state),
+        KeyIn::Unicode(c) => put_decoded(c, state),
+        KeyIn::Escape(e) => escape_handlers::handle_escape(e, state)
+    }
+}
+
+/// Prints a key typed by the user when local echo is on, applying the echo mappings. Kept apart 
+/// from the data received, so it doesn't break up characters being decoded or timestamp lines. 
+pub fn echo_key(key: KeyIn, state: &mut State) -> Result<(), io::Error> {
+    if state.display != DisplayMode::Text { return print_data_in(key, state) }
+    match key {
+        KeyIn::Nothing => Ok(()),
+        KeyIn::Char(c) => map_byte(c, &state.emap, state.tab_width as usize)
+            .into_iter()
+            .try_for_each(|b| echo_char(b as char, state)),
+        KeyIn::Unicode(c) => echo_char(c, state),
         KeyIn::Escape(e) => escape_handlers::handle_escape(e, state)
     }
 }
 
-/// Prints a character or actions a control code. 
+/// Prints a character typed by the user as it was typed, or actions a control code. 
+fn echo_char(c: char, state: &mut State) -> Result<(), io::Error> {
+    if c < ' ' || c == '\x7f' { return handle_control_char(c as u8, state) }
+    advance(c.encode_utf8(&mut [0; 4]), state);
+    put_string(format!("{}", state.style.apply_to(c)));
+    Ok(())
+}
+
+/// Prints a character received or actions a control code, applying the input mappings. 
 pub fn print_char(key: u8, state: &mut State) -> Result<(), io::Error> {
-    if key < 32 || key == 127 { return handle_control_char(key, state) }
-    let keychar = key as char;
-    put_string(format!("{}", keychar));
+    for b in map_byte(key, &state.imap, state.tab_width as usize) { put_byte(b, state)? }
+    Ok(())
+}
+
+/// Decodes a byte in the `--charset` character set, printing any character or invalid bytes it 
+/// completes. 
+fn put_byte(key: u8, state: &mut State) -> Result<(), io::Error> {
+    if key >= 0x80 && state.charset != Charset::Utf8 {
+        return put_decoded(charset::decode(key, state.charset), state);
+    }
+    for decoded in state.utf8.push(key) {
+        match decoded {
+            Decoded::Char(c) => put_decoded(c, state)?,
+            Decoded::Invalid(bytes) => put_invalid(&bytes, state)?,
+        }
+    }
+    Ok(())
+}
+
+/// Prints a character or actions a control code, prefixing it with a timestamp if it starts a line. 
+fn put_decoded(c: char, state: &mut State) -> Result<(), io::Error> {
+    if state.line_start && state.timestamp != Timestamp::Off {
+        let prefix = timestamp_prefix(state.timestamp, &state.timestamp_format, state.session_start, &mut state.last_line);
+        advance(&prefix, state);
+        put_string(prefix);
+    }
+    state.line_start = c == '\n';
+    if c < ' ' || c == '\x7f' { return handle_control_char(c as u8, state) }
+    let c = charset::translate(c, state.graphic_sets[state.shift_out as usize]);
+    advance(c.encode_utf8(&mut [0; 4]), state);
+    put_string(format!("{}", state.style.apply_to(c)));
     Ok(())
 }
 
+/// Prints the start of a character that's waited too long for the rest of it as invalid. 
+pub fn flush_stale_char(state: &mut State) -> Result<(), io::Error> {
+    match state.utf8.flush_stale() {
+        Some(bytes) => put_invalid(&bytes, state),
+        None => Ok(())
+    }
+}
+
+/// Prints bytes received that aren't valid UTF-8 as the `--invalid-utf8` policy says. 
+fn put_invalid(bytes: &[u8], state: &mut State) -> Result<(), io::Error> {
+    match state.invalid_utf8 {
+        InvalidUtf8::Replace => put_decoded(char::REPLACEMENT_CHARACTER, state),
+        InvalidUtf8::Hex => {
+            let hex: String = bytes.iter().map(|b| format!("[{:02x}]", b)).collect();
+            hex.chars().try_for_each(|c| put_decoded(c, state))
+        },
+        InvalidUtf8::Latin1 => bytes.iter().try_for_each(|b| put_decoded(*b as char, state)),
+    }
+}
+
+/// Adds a byte to the hex dump, redrawing the current row with the offset, the bytes in hex and 
+/// the printable ones as ASCII, and starting a new row once 16 bytes have been shown. 
+pub fn print_hex(byte: u8, state: &mut State) {
+    state.hex_row.push(byte);
+    let mut hex = String::with_capacity(50);
+    for (i, b) in state.hex_row.iter().enumerate() {
+        if i == 8 { hex.push(' ') }
+        hex.push_str(&format!("{:02x} ", b));
+    }
+    let ascii: String = state.hex_row.iter()
+        .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+        .collect();
+    put_string(format!("\r{:08x}  {:<49} |{}|", state.hex_offset, hex, ascii));
+
+    if state.hex_row.len() == 16 {
+        put_string("\r\n".to_string());
+        state.hex_offset += 16;
+        state.hex_row.clear();
+    }
+}
+
+/// Ends any part row of the hex dump and starts the offsets from 0 again. 
+pub fn reset_hex(state: &mut State) {
+    if !state.hex_row.is_empty() { put_string("\r\n".to_string()) }
+    state.hex_offset = 0;
+    state.hex_row.clear();
+}
+
 /// Actions a control code on to the terminal. 
 fn handle_control_char(key: u8, state: &mut State) -> Result<(), io::Error> {
     match key {
-        1 => state.term.clear_screen()?,
-        9 => state.term.move_cursor_right(TABS)?,
-        127 | 8 => state.term.clear_chars(1)?,
-        13 => state.term.write_line("")?,
+        1 => {
+            state.column = 0;
+            state.term.clear_screen()?
+        },
+        5 => {
+            let answer = state.answerback.clone();
+            state.reply(answer.as_bytes());
+        },
+        9 => escape_handlers::tab(state)?,
+        14 => state.shift_out = true,
+        15 => state.shift_out = false,
+        127 | 8 => {
+            state.column = state.column.saturating_sub(1);
+            state.term.clear_chars(1)?
+        },
+        13 => {
+            state.column = 0;
+            state.term.write(b"\x0D").map(|_| ())?
+        },
         10 => state.term.write(b"\x0A").map(|_| ())?,
         _ => put_char(key as char),
     }
     state.term.flush()
 }
+
+/// Moves the column the cursor is followed at past text about to be printed, wrapping to the start 
+/// of the next line when it goes past the edge of the terminal. 
+fn advance(text: &str, state: &mut State) {
+    let cols = state.term.size().1;
+    for c in text.chars() {
+        let width = console::measure_text_width(c.encode_utf8(&mut [0; 4])) as u16;
+        if width == 0 { continue }
+        if state.column + width > cols { state.column = 0 }
+        state.column += width;
+    }
+}
+
+/// Generates the timestamp a line is prefixed with in the given mode, updating when the previous 
+/// line started. 
+/// 
+/// * `format` - The strftime format used for the time of day. 
+/// * `start` - When the session started. 
+/// * `last` - When the previous line started. 
+pub fn timestamp_prefix(mode: Timestamp, format: &str, start: Instant, last: &mut Instant) -> String {
+    let now = Instant::now();
+    let prefix = match mode {
+        Timestamp::Off => String::new(),
+        Timestamp::Absolute => format!("[{}] ", chrono::Local::now().format(format)),
+        Timestamp::Session => format!("[{:>10.3}] ", (now - start).as_secs_f64()),
+        Timestamp::Delta => format!("[+{:>9.3}] ", (now - *last).as_secs_f64()),
+    };
+    *last = now;
+    prefix
+}
@@ -0,0 +1,128 @@
+use std::io;
+
+
+/// Sets mark (`mark` is true) or space parity on the port, using the `CMSPAR` termios flag.
+#[cfg(target_os = "linux")]
+pub fn set_mark_space(fd: i32, mark: bool) -> io::Result<()> {
+    let mut termios = get_termios(fd)?;
+    termios.c_cflag |= libc::PARENB | libc::CMSPAR;
+    if mark {
+        termios.c_cflag |= libc::PARODD;
+    } else {
+        termios.c_cflag &= !libc::PARODD;
+    }
+    set_termios(fd, &termios)
+}
+
+/// Clears the `CMSPAR` termios flag, so odd and even parity set through serialport aren't
+/// turned in to mark and space.
+#[cfg(target_os = "linux")]
+pub fn clear_mark_space(fd: i32) -> io::Result<()> {
+    let mut termios = get_termios(fd)?;
+    if termios.c_cflag & libc::CMSPAR == 0 { return Ok(()) }
+    termios.c_cflag &= !libc::CMSPAR;
+    set_termios(fd, &termios)
+}
+
+/// The termios settings of a port from before they were changed, for the Linux only flags the 
+/// settings saved through serialport don't cover, `HUPCL` and `CMSPAR` for example. 
+#[cfg(target_os = "linux")]
+pub struct Saved {
+    /// Every termios setting. 
+    termios: libc::termios,
+}
+
+/// Saves the termios settings of the port. 
+#[cfg(target_os = "linux")]
+pub fn save(fd: i32) -> io::Result<Saved> {
+    Ok(Saved { termios: get_termios(fd)? })
+}
+
+/// Puts back the saved termios settings, once anything still being sent has gone. `HUPCL` is put 
+/// back too, so closing the port afterwards hangs up the line if it did originally. 
+#[cfg(target_os = "linux")]
+pub fn restore(fd: i32, saved: &Saved) -> io::Result<()> {
+    if unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, &saved.termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Clears the `HUPCL` termios flag, so DTR isn't dropped when the port is closed and a connected 
+/// modem stays connected. 
+#[cfg(target_os = "linux")]
+pub fn keep_lines(fd: i32) -> io::Result<()> {
+    let mut termios = get_termios(fd)?;
+    if termios.c_cflag & libc::HUPCL == 0 { return Ok(()) }
+    termios.c_cflag &= !libc::HUPCL;
+    set_termios(fd, &termios)
+}
+
+/// Reads whether the DTR and RTS lines are up. 
+#[cfg(target_os = "linux")]
+pub fn modem_lines(fd: i32) -> io::Result<(bool, bool)> {
+    let mut modem: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::TIOCMGET, &mut modem) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((modem & libc::TIOCM_DTR != 0, modem & libc::TIOCM_RTS != 0))
+}
+
+/// Reads the termios settings of the port.
+#[cfg(target_os = "linux")]
+fn get_termios(fd: i32) -> io::Result<libc::termios> {
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(termios)
+}
+
+/// Writes the termios settings of the port, taking effect immediately.
+#[cfg(target_os = "linux")]
+fn set_termios(fd: i32, termios: &libc::termios) -> io::Result<()> {
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Mark and space parity need the Linux only `CMSPAR` termios flag.
+#[cfg(not(target_os = "linux"))]
+pub fn set_mark_space(_fd: i32, _mark: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "mark and space parity are only supported on Linux"))
+}
+
+/// There is no `CMSPAR` to clear outside of Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn clear_mark_space(_fd: i32) -> io::Result<()> {
+    Ok(())
+}
+
+/// There are no termios settings beyond what serialport covers outside of Linux. 
+#[cfg(not(target_os = "linux"))]
+pub struct Saved;
+
+/// There is nothing to save beyond what serialport covers outside of Linux. 
+#[cfg(not(target_os = "linux"))]
+pub fn save(_fd: i32) -> io::Result<Saved> {
+    Ok(Saved)
+}
+
+/// There is nothing saved to restore beyond what serialport covers outside of Linux. 
+#[cfg(not(target_os = "linux"))]
+pub fn restore(_fd: i32, _saved: &Saved) -> io::Result<()> {
+    Ok(())
+}
+
+/// There is no `HUPCL` to clear outside of Linux. 
+#[cfg(not(target_os = "linux"))]
+pub fn keep_lines(_fd: i32) -> io::Result<()> {
+    Ok(())
+}
+
+/// The modem lines can only be read on Linux. 
+#[cfg(not(target_os = "linux"))]
+pub fn modem_lines(_fd: i32) -> io::Result<(bool, bool)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "modem lines can only be read on Linux"))
+}
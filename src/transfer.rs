@@ -0,0 +1,428 @@
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serialport::{DataBits, SerialPort};
+
+use crate::args::Protocol;
+use crate::inputstream::InputStream;
+use crate::key::KeyIn;
+use crate::logfile::LogFile;
+use crate::serialstream::SerialStream;
+use crate::state::State;
+use crate::utils::put_string;
+
+/// Contains the sender and receiver for the XMODEM family of protocols.
+pub mod xmodem;
+/// Contains the batch sender and receiver for YMODEM.
+pub mod ymodem;
+/// Contains the streaming sender and receiver for ZMODEM.
+pub mod zmodem;
+/// Contains the windowed sender and receiver for Kermit.
+pub mod kermit;
+/// Contains the paced sender for uploading text files as is.
+pub mod ascii;
+/// Contains an in memory serial line to test the protocols over.
+#[cfg(test)]
+mod testlink;
+
+
+/// How long a single read of the serial port may block before checking if the user has cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The cancel byte (CAN) shared by the XMODEM family of protocols.
+pub const CAN: u8 = 0x18;
+
+/// Reasons a file transfer can fail.
+#[derive(Debug)]
+pub enum TransferError {
+    /// The user pressed the escape key during the transfer.
+    Cancelled,
+    /// The remote end sent a cancel sequence.
+    RemoteCancelled,
+    /// Gave up after the remote failed to respond correctly too many times.
+    TooManyRetries,
+    /// The remote didn't start the transfer in time.
+    TimedOut,
+    /// The remote sent something the protocol doesn't allow.
+    Protocol(&'static str),
+    /// Failed to read from or write to the serial port.
+    Port(io::Error),
+    /// Failed to read from or write to the local file.
+    File(io::Error),
+    /// The transfer was asked for in a way the protocol can't do.
+    Unsupported(&'static str),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Cancelled => write!(f, "cancelled by user"),
+            TransferError::RemoteCancelled => write!(f, "cancelled by remote"),
+            TransferError::TooManyRetries => write!(f, "too many retries"),
+            TransferError::TimedOut => write!(f, "timed out waiting for remote"),
+            TransferError::Protocol(e) => write!(f, "protocol error, {}", e),
+            TransferError::Port(e) => write!(f, "serial port error, {}", e),
+            TransferError::File(e) => write!(f, "file error, {}", e),
+            TransferError::Unsupported(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Wraps the serial port, the data received from it and the user input for the duration of a
+/// transfer, giving the protocols timed reads and a way of checking if the user has cancelled.
+///
+/// The port timeout is shortened while the link is alive and restored when it is dropped.
+pub struct Link<'a> {
+    port: &'a mut dyn SerialPort,
+    serial: &'a mut SerialStream,
+    input_stream: Option<&'a InputStream>,
+    escape_code: u8,
+    old_timeout: Duration,
+    log: Option<&'a mut LogFile>,
+    log_sent: bool,
+}
+
+impl<'a> Link<'a> {
+
+    /// Creates a new link over the given port.
+    ///
+    /// * `port` - The serial port to transfer over.
+    /// * `serial` - The data received from the serial port.
+    /// * `input_stream` - The user input, polled for the escape key to cancel the transfer, if any.
+    /// * `escape_code` - The byte that will cancel the transfer.
+    /// * `log` - The session log, every byte received is written to it.
+    /// * `log_sent` - Also write every byte sent to the session log.
+    pub fn new(port: &'a mut dyn SerialPort, 
+        serial: &'a mut SerialStream, 
+        input_stream: Option<&'a InputStream>, 
+        escape_code: u8,
+        log: Option<&'a mut LogFile>,
+        log_sent: bool) -> Link<'a> 
+    {
+        let old_timeout = port.timeout();
+        let _ = port.set_timeout(POLL_INTERVAL);
+        Link { port, serial, input_stream, escape_code, old_timeout, log, log_sent }
+    }
+
+    /// Creates a new link over the port in the state, logging to the session log if it's running.
+    ///
+    /// * `input_stream` - The user input, polled for the escape key to cancel the transfer, if any.
+    pub fn for_state(state: &'a mut State, input_stream: Option<&'a InputStream>) -> Link<'a> {
+        Link::new(state.port.as_mut(), 
+            &mut state.serial, 
+            input_stream, 
+            state.escape_code, 
+            state.log.as_mut(), 
+            state.log_sent)
+    }
+
+    /// Reads a single byte from the port, waiting for up to `timeout`, returns `None` if nothing arrived.
+    pub fn read_byte(&mut self, timeout: Duration) -> Result<Option<u8>, TransferError> {
+        let deadline = Instant::now() + timeout;
+        let res = loop {
+            if let Some(v) = self.serial.get_byte().map_err(TransferError::Port)? { break Some(v) }
+            self.check_cancel()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.serial.wait_byte(Some(remaining.min(POLL_INTERVAL))).map_err(TransferError::Port)? {
+                Some(v) => break Some(v),
+                None if remaining.is_zero() => break None,
+                None => continue
+            }
+        };
+        if let Some(v) = res { self.log(&[v]) }
+        Ok(res)
+    }
+
+    /// Reads exactly `buf.len()` bytes, allowing up to `timeout` between each byte, returns `false`
+    /// if the remote went quiet before the buffer was filled.
+    pub fn read_exact(&mut self, buf: &mut [u8], timeout: Duration) -> Result<bool, TransferError> {
+        for b in buf.iter_mut() {
+            match self.read_byte(timeout)? {
+                Some(v) => *b = v,
+                None => return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes all the bytes to the port, waiting for space in the output buffer if needed.
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), TransferError> {
+        if self.log_sent { self.log(data) }
+        let mut data = data;
+        while !data.is_empty() {
+            self.check_cancel()?;
+            match self.port.write(data) {
+                Ok(n) => data = &data[n..],
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(TransferError::Port(e))
+            }
+        }
+        self.port.flush().map_err(TransferError::Port)
+    }
+
+    /// Returns true if there is received data waiting to be read.
+    pub fn pending(&mut self) -> bool {
+        self.serial.has_data()
+    }
+
+    /// Discards any received data until the line has been quiet for `quiet`.
+    pub fn purge(&mut self, quiet: Duration) -> Result<(), TransferError> {
+        while self.read_byte(quiet)?.is_some() {}
+        Ok(())
+    }
+
+    /// Writes bytes to the session log if it's running, stopping logging for the rest of the 
+    /// transfer if it can't be written to.
+    fn log(&mut self, data: &[u8]) {
+        let log = match self.log.as_mut() {
+            Some(v) => v,
+            None => return
+        };
+        if let Err(e) = log.write(data) {
+            println!("\r\n*** Failed to write to log file, logging stopped, reason: \r\n{} \r\n", e);
+            self.log = None;
+        }
+    }
+
+    /// Throws [TransferError::Cancelled] if the user has pressed the escape key or the input has gone.
+    fn check_cancel(&self) -> Result<(), TransferError> {
+        let input_stream = match self.input_stream {
+            Some(v) => v,
+            None => return Ok(())
+        };
+        match input_stream.get_char() {
+            Some(Ok(KeyIn::Char(c))) if c == self.escape_code => Err(TransferError::Cancelled),
+            Some(Err(_)) => Err(TransferError::Cancelled),
+            _ => Ok(())
+        }
+    }
+}
+
+impl Drop for Link<'_> {
+    fn drop(&mut self) {
+        let _ = self.port.set_timeout(self.old_timeout);
+        if let Some(log) = self.log.as_mut() { let _ = log.flush(); }
+    }
+}
+
+/// Keeps track of and displays the progress of a transfer on a single line of the terminal.
+pub struct Progress {
+    name: String,
+    total: Option<u64>,
+    bytes: u64,
+    retries: u32,
+    start: Instant,
+}
+
+impl Progress {
+
+    /// Creates a new progress line for a transfer.
+    ///
+    /// * `name` - The name of the protocol, shown at the start of the line.
+    /// * `total` - The size of the file being transferred, if known.
+    pub fn new(name: &str, total: Option<u64>) -> Progress {
+        Progress { name: name.to_string(), total, bytes: 0, retries: 0, start: Instant::now() }
+    }
+
+    /// Adds to the number of bytes transferred and redraws the line.
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.show();
+    }
+
+    /// Sets the number of bytes transferred, for when a transfer restarts from a position, and 
+    /// redraws the line.
+    pub fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = bytes;
+        self.show();
+    }
+
+    /// Adds a retry and redraws the line.
+    pub fn add_retry(&mut self) {
+        self.retries += 1;
+        self.show();
+    }
+
+    /// Redraws the progress line.
+    pub fn show(&self) {
+        let percent = match self.total {
+            Some(t) if t > 0 => format!(" ({}%)", (self.bytes * 100 / t).min(100)),
+            _ => String::new(),
+        };
+        put_string(format!("\r*** {}: {} bytes{}, {} retries, {}s ",
+            self.name,
+            self.bytes,
+            percent,
+            self.retries,
+            self.start.elapsed().as_secs()
+        ));
+    }
+}
+
+/// Sends the files at `paths` over the serial port using the protocol selected in the state, only
+/// batch protocols can send more than one file.
+///
+/// * `input_stream` - The user input to watch for the escape key, if `None` the transfer can't be cancelled.
+pub fn send_files(paths: &[String], state: &mut State, input_stream: Option<&InputStream>) -> Result<u64, TransferError> {
+    let (protocol, flow, resume) = (state.protocol, state.flow, state.resume);
+    if paths.len() != 1 && !protocol.is_batch() {
+        return Err(TransferError::Unsupported("protocol can only send one file at a time"));
+    }
+    let seven_bit = is_seven_bit(state);
+    let mut link = Link::for_state(state, input_stream);
+
+    let res = match protocol {
+        Protocol::Xmodem | Protocol::XmodemCrc =>
+            send_xmodem(&paths[0], xmodem::BlockSize::Short, protocol, &mut link),
+        Protocol::Xmodem1k =>
+            send_xmodem(&paths[0], xmodem::BlockSize::Long, protocol, &mut link),
+        Protocol::Ymodem => ymodem::send(&mut link, paths),
+        Protocol::Zmodem => zmodem::send(&mut link, paths, flow, resume),
+        Protocol::Kermit => kermit::send(&mut link, paths, seven_bit),
+    };
+    finish(res, &mut link)
+}
+
+/// Receives files over the serial port using the protocol selected in the state.
+///
+/// * `path` - The path to save the file to, required by protocols that don't send file names,
+///   batch protocols save files in to the receive directory instead.
+/// * `input_stream` - The user input to watch for the escape key, if `None` the transfer can't be cancelled.
+pub fn receive_files(path: Option<&str>, state: &mut State, input_stream: Option<&InputStream>) -> Result<u64, TransferError> {
+    let (protocol, resume) = (state.protocol, state.resume);
+    if path.is_none() && !protocol.is_batch() {
+        return Err(TransferError::Unsupported("protocol needs a file name to receive in to"));
+    }
+    let dir = state.receive_dir.clone();
+    let seven_bit = is_seven_bit(state);
+    let mut link = Link::for_state(state, input_stream);
+
+    let res = match (protocol, path) {
+        (Protocol::Xmodem, Some(path)) =>
+            receive_xmodem(path, xmodem::Check::Checksum, protocol, &mut link),
+        (Protocol::XmodemCrc, Some(path)) | (Protocol::Xmodem1k, Some(path)) =>
+            receive_xmodem(path, xmodem::Check::Crc, protocol, &mut link),
+        (Protocol::Ymodem, _) => ymodem::receive(&mut link, &dir),
+        (Protocol::Zmodem, _) => zmodem::receive(&mut link, &dir, resume),
+        (Protocol::Kermit, _) => kermit::receive(&mut link, &dir, seven_bit),
+        _ => Ok(0),
+    };
+    finish(res, &mut link)
+}
+
+/// Receives files with ZMODEM after the start of a send was spotted in the data from the port, 
+/// regardless of the selected protocol. 
+pub fn autostart_zmodem(state: &mut State, input_stream: &InputStream) -> Result<u64, TransferError> {
+    let dir = state.receive_dir.clone();
+    let resume = state.resume;
+    let mut link = Link::for_state(state, Some(input_stream));
+    let res = zmodem::receive(&mut link, &dir, resume);
+    finish(res, &mut link)
+}
+
+/// Uploads a text file as is, rather than with a transfer protocol, paced by the delays, line
+/// ending and prompt set in the state.
+///
+/// * `input_stream` - The user input to watch for the escape key, if `None` the upload can't be cancelled.
+pub fn send_ascii(path: &str, state: &mut State, input_stream: Option<&InputStream>) -> Result<u64, TransferError> {
+    let data = std::fs::read(path).map_err(TransferError::File)?;
+    let name = Path::new(path).file_name().map(|v| v.to_string_lossy().to_string()).unwrap_or_default();
+    let mut progress = Progress::new(&format!("ascii {}", name), Some(data.len() as u64));
+    let pacing = ascii::Pacing {
+        char_delay: state.char_delay,
+        line_delay: state.line_delay,
+        eol: state.eol,
+        prompt: state.prompt.clone(),
+    };
+    let mut link = Link::for_state(state, input_stream);
+    ascii::send(&mut link, &data, &pacing, &mut progress)
+}
+
+/// Returns true if the port has less than 8 data bits, so can't carry binary data as is.
+fn is_seven_bit(state: &State) -> bool {
+    !matches!(state.port.data_bits(), Ok(DataBits::Eight) | Err(_))
+}
+
+/// Sends a single file with one of the XMODEM protocols.
+fn send_xmodem(path: &str, size: xmodem::BlockSize, protocol: Protocol, link: &mut Link) -> Result<u64, TransferError> {
+    let mut file = File::open(path).map_err(TransferError::File)?;
+    let total = file.metadata().map(|m| m.len()).ok();
+    let mut progress = Progress::new(protocol.show(), total);
+    xmodem::send(link, &mut file, size, &mut progress)
+}
+
+/// Receives a single file with one of the XMODEM protocols.
+fn receive_xmodem(path: &str, check: xmodem::Check, protocol: Protocol, link: &mut Link) -> Result<u64, TransferError> {
+    let mut file = File::create(path).map_err(TransferError::File)?;
+    let mut progress = Progress::new(protocol.show(), None);
+    xmodem::receive(link, &mut file, check, &mut progress)
+}
+
+/// Tells the remote to abort if the transfer failed on our end, and discards anything left on the line.
+fn finish(res: Result<u64, TransferError>, link: &mut Link) -> Result<u64, TransferError> {
+    match &res {
+        Err(TransferError::RemoteCancelled) | Err(TransferError::Port(_)) | Ok(_) => (),
+        Err(_) => {
+            let mut abort = [CAN; 16];
+            abort[8..].fill(0x08);
+            if link.log_sent { link.log(&abort) }
+            let _ = link.port.write_all(&abort);
+            let _ = link.port.flush();
+        }
+    }
+    let _ = link.purge(Duration::from_millis(500));
+    res
+}
+
+/// Calculates the CRC-32 (IEEE, reflected polynomial `0xEDB88320`) of the given data as used by ZMODEM.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Calculates the CRC-16/XMODEM (CCITT polynomial `0x1021`, zero initial value) of the given data.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::LogMode;
+
+    /// Bytes received and sent over a link go in to the session log, sent bytes only if asked for.
+    #[test]
+    fn link_logs_traffic() {
+        for log_sent in [false, true] {
+            let path = testlink::temp_dir(&format!("log-{}", log_sent)).join("session.log");
+            let mut log = LogFile::open(&path, LogMode::Truncate).unwrap();
+            let (a, mut b) = testlink::pipe();
+            a.send(b"\x01abc");
+            let mut serial = b.stream();
+            let mut link = Link::new(&mut b, &mut serial, None, 0x02, Some(&mut log), log_sent);
+            assert_eq!(link.read_byte(Duration::from_secs(1)).unwrap(), Some(0x01));
+            link.write_all(b"xyz").unwrap();
+            link.purge(Duration::from_millis(100)).unwrap();
+            drop(link);
+            let expected: &[u8] = if log_sent { b"\x01xyzabc" } else { b"\x01abc" };
+            assert_eq!(std::fs::read(&path).unwrap(), expected);
+        }
+    }
+}
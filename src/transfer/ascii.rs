@@ -0,0 +1,118 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use regex::bytes::Regex;
+
+use super::{Link, Progress, TransferError};
+use crate::args::Eol;
+
+
+/// How long to wait for the prompt after sending a line before giving up.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How an upload is paced.
+pub struct Pacing {
+    /// The delay after each character.
+    pub char_delay: Duration,
+    /// The delay after each line.
+    pub line_delay: Duration,
+    /// What line endings are translated to.
+    pub eol: Eol,
+    /// Wait for something matching this after each line before sending the next, if given.
+    pub prompt: Option<Regex>,
+}
+
+/// Shows what the device sends back during the upload, keeping the progress line below it.
+///
+/// Only whole lines are printed while the upload is running, so the progress line doesn't
+/// overwrite anything.
+struct Echo {
+    /// The end of the current line, printed once the rest of it arrives.
+    partial: Vec<u8>,
+    /// Everything received since the last line was sent, matched against the prompt.
+    seen: Vec<u8>,
+}
+
+impl Echo {
+    /// Adds a byte received from the device.
+    fn push(&mut self, byte: u8, progress: &Progress) {
+        self.seen.push(byte);
+        self.partial.push(byte);
+        if byte == b'\n' {
+            self.print();
+            progress.show();
+        }
+    }
+
+    /// Prints the line received so far over the progress line.
+    fn print(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\r\x1b[2K");
+        let _ = stdout.write_all(&self.partial);
+        let _ = stdout.flush();
+        self.partial.clear();
+    }
+}
+
+/// Sends the data a line at a time, pausing after each character and line and waiting for the
+/// prompt as set in `pacing`, returning the number of bytes of the file sent.
+pub fn send(link: &mut Link, data: &[u8], pacing: &Pacing, progress: &mut Progress) -> Result<u64, TransferError> {
+    let mut echo = Echo { partial: vec![], seen: vec![] };
+    let mut sent = 0u64;
+    progress.show();
+
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let mut out = line.to_vec();
+        if let Some(eol) = pacing.eol.sequence() {
+            while out.last().is_some_and(|b| *b == b'\n' || *b == b'\r') { out.pop(); }
+            out.extend_from_slice(eol);
+        }
+
+        echo.seen.clear();
+        if pacing.char_delay.is_zero() {
+            link.write_all(&out)?;
+        } else {
+            for byte in out {
+                link.write_all(&[byte])?;
+                pump(link, &mut echo, progress, pacing.char_delay)?;
+            }
+        }
+        sent += line.len() as u64;
+        progress.add_bytes(line.len() as u64);
+        pump(link, &mut echo, progress, pacing.line_delay)?;
+
+        if let Some(prompt) = &pacing.prompt {
+            wait_for_prompt(link, &mut echo, progress, prompt)?;
+        }
+    }
+
+    pump(link, &mut echo, progress, Duration::ZERO)?;
+    echo.print();
+    Ok(sent)
+}
+
+/// Shows anything received from the device for the given time.
+fn pump(link: &mut Link, echo: &mut Echo, progress: &Progress, time: Duration) -> Result<(), TransferError> {
+    let deadline = Instant::now() + time;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match link.read_byte(remaining)? {
+            Some(byte) => echo.push(byte, progress),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Shows anything received from the device until something matching the prompt has been received
+/// since the last line was sent.
+fn wait_for_prompt(link: &mut Link, echo: &mut Echo, progress: &Progress, prompt: &Regex) -> Result<(), TransferError> {
+    let deadline = Instant::now() + PROMPT_TIMEOUT;
+    while !prompt.is_match(&echo.seen) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match link.read_byte(remaining)? {
+            Some(byte) => echo.push(byte, progress),
+            None => return Err(TransferError::TimedOut),
+        }
+    }
+    Ok(())
+}
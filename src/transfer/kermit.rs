@@ -0,0 +1,797 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use super::xmodem::read_full;
+use super::ymodem::FileInfo;
+use super::{Link, Progress, TransferError};
+
+
+/// Start of every packet.
+const MARK: u8 = 0x01;
+/// End of every packet.
+const EOL: u8 = b'\r';
+/// Control character prefix.
+const QCTL: u8 = b'#';
+/// 8th bit prefix asked for on 7 bit links.
+const QBIN: u8 = b'&';
+/// Repeat count prefix.
+const REPT: u8 = b'~';
+
+/// Packet types.
+const SEND_INIT: u8 = b'S';
+const FILE_HEADER: u8 = b'F';
+const ATTRIBUTES: u8 = b'A';
+const DATA: u8 = b'D';
+const END_OF_FILE: u8 = b'Z';
+const BREAK: u8 = b'B';
+const ACK: u8 = b'Y';
+const NAK: u8 = b'N';
+const ERROR: u8 = b'E';
+
+/// Capability bits in the CAPAS field.
+const CAPA_LONG: u8 = 2;
+const CAPA_WINDOWS: u8 = 4;
+const CAPA_ATTRIBUTES: u8 = 8;
+
+/// The longest normal packet we can receive.
+const MAXL: usize = 94;
+/// The longest extended packet we can receive.
+const MAXLX: usize = 4000;
+/// The largest sliding window we offer.
+const WINDOW: usize = 16;
+/// The timeout we ask the other end to use, in seconds.
+const TIME: u8 = 5;
+
+/// How many times a packet is resent before giving up.
+const MAX_RETRIES: u32 = 10;
+/// How long to wait for a packet.
+const PACKET_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait between bytes within a packet.
+const BYTE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Makes a number from 0 to 94 printable.
+fn tochar(x: usize) -> u8 {
+    (x as u8).wrapping_add(32)
+}
+
+/// Undoes [tochar].
+fn unchar(c: u8) -> usize {
+    c.wrapping_sub(32) as usize
+}
+
+/// Toggles a control character to and from its printable form.
+fn ctl(c: u8) -> u8 {
+    c ^ 64
+}
+
+/// Returns true if the character is allowed as a prefix.
+fn is_prefix(c: u8) -> bool {
+    matches!(c, 33..=62 | 96..=126)
+}
+
+/// Calculates the block check of the given type over the packet from LEN to the end of DATA.
+///
+/// * `1` - 6 bit sum, one character.
+/// * `2` - 12 bit sum, two characters.
+/// * `3` - CRC-CCITT, three characters.
+fn block_check(check: u8, data: &[u8]) -> Vec<u8> {
+    let sum: usize = data.iter().map(|b| *b as usize).sum();
+    match check {
+        2 => vec![tochar((sum >> 6) & 0x3F), tochar(sum & 0x3F)],
+        3 => {
+            let mut crc = 0u16;
+            for b in data {
+                crc ^= *b as u16;
+                for _ in 0..8 {
+                    crc = if crc & 1 != 0 { (crc >> 1) ^ 0x8408 } else { crc >> 1 };
+                }
+            }
+            let crc = crc as usize;
+            vec![tochar((crc >> 12) & 0x0F), tochar((crc >> 6) & 0x3F), tochar(crc & 0x3F)]
+        },
+        _ => vec![tochar((sum + ((sum & 0xC0) >> 6)) & 0x3F)],
+    }
+}
+
+/// The settings agreed in the Send-Init exchange.
+#[derive(Debug, Clone, Copy)]
+struct Params {
+    /// The block check type (1, 2 or 3).
+    check: u8,
+    /// The 8th bit prefix, if in use.
+    qbin: Option<u8>,
+    /// The repeat prefix, if in use.
+    rept: Option<u8>,
+    /// The most data that can be put in a packet we send.
+    max_data: usize,
+    /// The number of packets that can be in flight.
+    window: usize,
+    /// Send attribute packets.
+    attributes: bool,
+    /// Mask applied to received bytes, strips parity on 7 bit links.
+    mask: u8,
+}
+
+impl Params {
+    /// The settings used before (and for) the Send-Init exchange.
+    fn initial(seven_bit: bool) -> Params {
+        Params {
+            check: 1,
+            qbin: None,
+            rept: None,
+            max_data: 80 - 5,
+            window: 1,
+            attributes: false,
+            mask: if seven_bit { 0x7F } else { 0xFF },
+        }
+    }
+
+    /// Our Send-Init parameters, sent in the S packet or in its ACK.
+    fn ours(seven_bit: bool) -> Vec<u8> {
+        vec![
+            tochar(MAXL),
+            tochar(TIME as usize),
+            tochar(0),
+            ctl(0),
+            tochar(EOL as usize),
+            QCTL,
+            if seven_bit { QBIN } else { b'Y' },
+            b'3',
+            REPT,
+            tochar((CAPA_LONG | CAPA_WINDOWS | CAPA_ATTRIBUTES) as usize),
+            tochar(WINDOW),
+            tochar(MAXLX / 95),
+            tochar(MAXLX % 95),
+        ]
+    }
+
+    /// Works out the settings to use from the other end's Send-Init parameters.
+    fn negotiate(seven_bit: bool, theirs: &[u8]) -> Params {
+        let field = |i: usize| theirs.get(i).copied().filter(|c| *c != b' ');
+        let mut res = Params::initial(seven_bit);
+
+        let maxl = field(0).map(unchar).unwrap_or(80).clamp(20, MAXL);
+        let ours_qbin = if seven_bit { QBIN } else { b'Y' };
+        res.qbin = match field(6) {
+            Some(c) if is_prefix(c) && (ours_qbin == b'Y' || ours_qbin == c) => Some(c),
+            Some(b'Y') if ours_qbin != b'Y' => Some(ours_qbin),
+            _ => None,
+        };
+        res.check = match field(7) {
+            Some(b'3') => 3,
+            _ => 1,
+        };
+        res.rept = field(8).filter(|c| *c == REPT);
+
+        // CAPAS can run over several bytes, each with the low bit set if another follows
+        let mut capas = 0u8;
+        let mut i = 9;
+        while let Some(c) = field(i) {
+            if i == 9 { capas = unchar(c) as u8 }
+            i += 1;
+            if unchar(c) & 1 == 0 { break }
+        }
+        let long = capas & CAPA_LONG != 0;
+        if capas & CAPA_WINDOWS != 0 {
+            res.window = field(i).map(unchar).unwrap_or(1).clamp(1, WINDOW);
+        }
+        res.attributes = capas & CAPA_ATTRIBUTES != 0;
+        let check_len = res.check as usize;
+        res.max_data = if long {
+            let maxlx = match (field(i + 1), field(i + 2)) {
+                (Some(a), Some(b)) => unchar(a) * 95 + unchar(b),
+                _ => 500,
+            };
+            maxlx.clamp(maxl, MAXLX) - check_len - 5
+        } else {
+            maxl - check_len - 2
+        };
+        res
+    }
+}
+
+/// A decoded packet.
+#[derive(Debug, Clone)]
+struct Packet {
+    seq: u8,
+    kind: u8,
+    data: Vec<u8>,
+}
+
+/// The result of reading a packet.
+enum Read {
+    Packet(Packet),
+    Bad,
+    Timeout,
+}
+
+/// Builds a packet, using the extended length format if the data doesn't fit a normal one.
+fn build_packet(seq: u8, kind: u8, data: &[u8], check: u8) -> Vec<u8> {
+    let check_len = check as usize;
+    let mut out = vec![MARK];
+    let mut body = if data.len() + check_len + 2 > MAXL {
+        let lenx = data.len() + check_len;
+        let mut body = vec![tochar(0), tochar(seq as usize), kind, tochar(lenx / 95), tochar(lenx % 95)];
+        let hcheck = block_check(1, &body);
+        body.extend(hcheck);
+        body
+    } else {
+        vec![tochar(data.len() + check_len + 2), tochar(seq as usize), kind]
+    };
+    body.extend_from_slice(data);
+    let check_bytes = block_check(check, &body);
+    out.extend(body);
+    out.extend(check_bytes);
+    out.push(EOL);
+    out
+}
+
+/// Reads the next packet, skipping anything before the MARK.
+fn read_packet(link: &mut Link, params: &Params, timeout: Duration) -> Result<Read, TransferError> {
+    loop {
+        match link.read_byte(timeout)? {
+            Some(c) if c & params.mask == MARK => break,
+            Some(_) => continue,
+            None => return Ok(Read::Timeout),
+        }
+    }
+    let next = |link: &mut Link| -> Result<Option<u8>, TransferError> {
+        Ok(link.read_byte(BYTE_TIMEOUT)?.map(|c| c & params.mask))
+    };
+
+    let mut body = vec![];
+    let mut header = [0u8; 3];
+    for b in header.iter_mut() {
+        match next(link)? {
+            Some(MARK) | None => return Ok(Read::Bad),
+            Some(c) => *b = c,
+        }
+    }
+    body.extend_from_slice(&header);
+    let len = if unchar(header[0]) == 0 {
+        let mut ext = [0u8; 3];
+        for b in ext.iter_mut() {
+            match next(link)? {
+                Some(MARK) | None => return Ok(Read::Bad),
+                Some(c) => *b = c,
+            }
+        }
+        body.extend_from_slice(&ext);
+        if block_check(1, &body[..5]) != [ext[2]] { return Ok(Read::Bad) }
+        unchar(ext[0]) * 95 + unchar(ext[1])
+    } else {
+        match unchar(header[0]).checked_sub(2) {
+            Some(v) => v,
+            None => return Ok(Read::Bad),
+        }
+    };
+
+    let mut rest = vec![];
+    while rest.len() < len {
+        match next(link)? {
+            Some(MARK) | None => return Ok(Read::Bad),
+            Some(c) => rest.push(c),
+        }
+    }
+
+    // The check type is settled in the Send-Init exchange, which is always sent with type 1
+    let check = if header[2] == SEND_INIT { 1 } else { params.check as usize };
+    if len < check { return Ok(Read::Bad) }
+    let (data, check_bytes) = rest.split_at(len - check);
+    body.extend_from_slice(data);
+    if block_check(check as u8, &body) != check_bytes { return Ok(Read::Bad) }
+
+    Ok(Read::Packet(Packet { seq: unchar(header[1]) as u8, kind: header[2], data: data.to_vec() }))
+}
+
+/// Encodes a single byte with control, 8th bit and repeat prefixing.
+fn encode_byte(out: &mut Vec<u8>, byte: u8, count: usize, params: &Params) {
+    if let Some(rept) = params.rept && count > 1 {
+        out.push(rept);
+        out.push(tochar(count));
+    }
+    let mut c = byte;
+    if let Some(qbin) = params.qbin && c & 0x80 != 0 {
+        out.push(qbin);
+        c &= 0x7F;
+    }
+    let a7 = c & 0x7F;
+    if a7 < 32 || a7 == 127 {
+        out.push(QCTL);
+        c = ctl(c);
+    } else if a7 == QCTL || Some(a7) == params.qbin || Some(a7) == params.rept {
+        out.push(QCTL);
+    }
+    out.push(c);
+}
+
+/// Encodes as much of `data` as fits in `max` bytes, returning the encoded bytes and how much
+/// of the data was used.
+fn encode(data: &[u8], max: usize, params: &Params) -> (Vec<u8>, usize) {
+    let mut out = Vec::with_capacity(max);
+    let mut used = 0;
+    while used < data.len() {
+        let byte = data[used];
+        let mut count = 1;
+        if params.rept.is_some() {
+            while used + count < data.len() && data[used + count] == byte && count < 94 { count += 1 }
+            if count < 3 { count = 1 }
+        }
+        let mut encoded = vec![];
+        encode_byte(&mut encoded, byte, count, params);
+        if out.len() + encoded.len() > max { break }
+        out.extend(encoded);
+        used += count;
+    }
+    (out, used)
+}
+
+/// Decodes the data field of a packet.
+fn decode(data: &[u8], params: &Params) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter().copied();
+    while let Some(mut c) = iter.next() {
+        let mut count = 1;
+        if Some(c) == params.rept {
+            count = iter.next().map(unchar).unwrap_or(1);
+            c = match iter.next() { Some(v) => v, None => break };
+        }
+        let mut high = 0;
+        if Some(c) == params.qbin {
+            high = 0x80;
+            c = match iter.next() { Some(v) => v, None => break };
+        }
+        if c == QCTL {
+            c = match iter.next() { Some(v) => v, None => break };
+            if matches!(c & 0x7F, 63..=95) { c = ctl(c) }
+        }
+        for _ in 0..count { out.push(c | high) }
+    }
+    out
+}
+
+/// Keeps track of the sequence numbers and sliding window on the sending side.
+struct Sender<'a, 'b> {
+    link: &'a mut Link<'b>,
+    params: Params,
+    seq: u8,
+}
+
+impl Sender<'_, '_> {
+    /// Sends a single packet and waits for it to be acknowledged, returning the ACK's data.
+    fn send_wait(&mut self, kind: u8, data: &[u8], progress: &mut Progress) -> Result<Vec<u8>, TransferError> {
+        let packet = build_packet(self.seq, kind, data, self.params.check);
+        for _ in 0..MAX_RETRIES {
+            self.link.write_all(&packet)?;
+            match read_packet(self.link, &self.params, PACKET_TIMEOUT)? {
+                Read::Packet(p) if p.kind == ACK && p.seq == self.seq => {
+                    self.seq = (self.seq + 1) % 64;
+                    return Ok(p.data);
+                },
+                Read::Packet(p) if p.kind == ERROR => return Err(remote_error(&p)),
+                _ => progress.add_retry(),
+            }
+        }
+        Err(TransferError::TooManyRetries)
+    }
+
+    /// Sends the contents of the file in data packets, keeping up to a window of packets in flight.
+    fn send_data(&mut self, file: &mut File, progress: &mut Progress) -> Result<u64, TransferError> {
+        let mut in_flight: VecDeque<(u8, Vec<u8>, bool, u64)> = VecDeque::new();
+        let mut buf = vec![0u8; self.params.max_data * 2];
+        let mut pending: Vec<u8> = vec![];
+        let mut eof = false;
+        let mut sent = 0u64;
+        let mut retries = 0;
+
+        loop {
+            while in_flight.len() < self.params.window && !(eof && pending.is_empty()) {
+                if !eof && pending.len() < self.params.max_data {
+                    let n = read_full(file, &mut buf)?;
+                    eof = n < buf.len();
+                    pending.extend_from_slice(&buf[..n]);
+                }
+                if pending.is_empty() { break }
+                let (encoded, used) = encode(&pending, self.params.max_data, &self.params);
+                pending.drain(..used);
+                let packet = build_packet(self.seq, DATA, &encoded, self.params.check);
+                self.link.write_all(&packet)?;
+                in_flight.push_back((self.seq, packet, false, used as u64));
+                self.seq = (self.seq + 1) % 64;
+            }
+            if in_flight.is_empty() { return Ok(sent) }
+
+            match read_packet(self.link, &self.params, PACKET_TIMEOUT)? {
+                Read::Packet(p) if p.kind == ACK => {
+                    if p.data.first().is_some_and(|c| *c == b'X' || *c == b'Z') {
+                        return Err(TransferError::RemoteCancelled);
+                    }
+                    if let Some(e) = in_flight.iter_mut().find(|e| e.0 == p.seq) { e.2 = true }
+                },
+                Read::Packet(p) if p.kind == NAK => {
+                    if let Some(e) = in_flight.iter().find(|e| e.0 == p.seq) {
+                        self.link.write_all(&e.1)?;
+                        retries += 1;
+                        progress.add_retry();
+                    } else if p.seq == self.seq {
+                        // A NAK for the next packet acknowledges everything before it
+                        in_flight.iter_mut().for_each(|e| e.2 = true);
+                    }
+                },
+                Read::Packet(p) if p.kind == ERROR => return Err(remote_error(&p)),
+                _ => {
+                    if let Some(e) = in_flight.iter().find(|e| !e.2) {
+                        self.link.write_all(&e.1)?;
+                    }
+                    retries += 1;
+                    progress.add_retry();
+                },
+            }
+            if retries >= MAX_RETRIES * self.params.window as u32 { return Err(TransferError::TooManyRetries) }
+            while in_flight.front().is_some_and(|e| e.2) {
+                if let Some(e) = in_flight.pop_front() {
+                    sent += e.3;
+                    progress.add_bytes(e.3);
+                }
+                retries = 0;
+            }
+        }
+    }
+}
+
+/// Turns an error packet from the other end in to a transfer error.
+fn remote_error(packet: &Packet) -> TransferError {
+    println!("\r\n*** kermit: remote error: {}\r", String::from_utf8_lossy(&packet.data));
+    TransferError::RemoteCancelled
+}
+
+/// Sends a batch of files.
+///
+/// * `seven_bit` - The port has less than 8 data bits, so 8th bit prefixing is asked for.
+pub fn send(link: &mut Link, paths: &[String], seven_bit: bool) -> Result<u64, TransferError> {
+    let mut sender = Sender { link, params: Params::initial(seven_bit), seq: 0 };
+    let mut progress = Progress::new("kermit", None);
+    let theirs = sender.send_wait(SEND_INIT, &Params::ours(seven_bit), &mut progress)?;
+    sender.params = Params::negotiate(seven_bit, &theirs);
+    if seven_bit && sender.params.qbin.is_none() {
+        println!("\r\n*** kermit: remote refused 8th bit prefixing, 8 bit data will be corrupted\r");
+    }
+
+    let mut sent = 0u64;
+    for path in paths {
+        let path = Path::new(path);
+        let mut file = File::open(path).map_err(TransferError::File)?;
+        let info = FileInfo::from_file(path, &file)?;
+        let mut progress = Progress::new(&format!("kermit {}", info.name), info.size);
+
+        let params = sender.params;
+        sender.send_wait(FILE_HEADER, &encode(info.name.as_bytes(), params.max_data, &params).0, &mut progress)?;
+        if params.attributes {
+            let size = info.size.unwrap_or(0).to_string();
+            let mut attrs = vec![b'1', tochar(size.len())];
+            attrs.extend(size.bytes());
+            let res = sender.send_wait(ATTRIBUTES, &attrs, &mut progress)?;
+            if res.first() == Some(&b'N') {
+                println!("\r\n*** kermit: {} refused by remote\r", info.name);
+                sender.send_wait(END_OF_FILE, b"D", &mut progress)?;
+                continue;
+            }
+        }
+        sent += sender.send_data(&mut file, &mut progress)?;
+        sender.send_wait(END_OF_FILE, &[], &mut progress)?;
+        println!("\r");
+    }
+    sender.send_wait(BREAK, &[], &mut progress)?;
+    Ok(sent)
+}
+
+/// Keeps track of the sequence numbers and sliding window on the receiving side.
+struct Receiver<'a, 'b> {
+    link: &'a mut Link<'b>,
+    params: Params,
+    /// The next sequence number to be handled.
+    expected: u8,
+    /// Packets received ahead of `expected`.
+    buffered: BTreeMap<u8, Packet>,
+    /// The last ACK sent for each sequence number, resent if the packet turns up again.
+    acks: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Receiver<'_, '_> {
+    /// Sends an ACK for a sequence number and remembers it.
+    fn ack(&mut self, seq: u8, data: &[u8]) -> Result<(), TransferError> {
+        let packet = build_packet(seq, ACK, data, self.params.check);
+        self.link.write_all(&packet)?;
+        self.acks.insert(seq, packet);
+        Ok(())
+    }
+
+    /// Sends a NAK for a sequence number.
+    fn nak(&mut self, seq: u8) -> Result<(), TransferError> {
+        let packet = build_packet(seq, NAK, &[], self.params.check);
+        self.link.write_all(&packet)
+    }
+
+    /// Returns the next packet in sequence, acknowledging packets as they arrive, NAKing any
+    /// missing from the window and resending ACKs for duplicates.
+    ///
+    /// Packets other than data are acknowledged by the caller, as their ACKs can carry data.
+    fn next(&mut self, progress: &mut Progress) -> Result<Packet, TransferError> {
+        let mut retries = 0;
+        loop {
+            if let Some(p) = self.buffered.remove(&self.expected) {
+                self.expected = (self.expected + 1) % 64;
+                return Ok(p);
+            }
+            if retries >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+
+            let packet = match read_packet(self.link, &self.params, PACKET_TIMEOUT)? {
+                Read::Packet(p) => p,
+                Read::Bad | Read::Timeout => {
+                    retries += 1;
+                    progress.add_retry();
+                    self.nak(self.expected)?;
+                    continue;
+                },
+            };
+            if packet.kind == ERROR { return Err(remote_error(&packet)) }
+
+            let expected = self.expected as usize;
+            let ahead_of = |seq: u8| (seq as usize + 64 - expected) % 64;
+            let ahead = ahead_of(packet.seq);
+            if ahead < self.params.window.max(1) {
+                retries = 0;
+                if packet.kind == DATA { self.ack(packet.seq, &[])? }
+                // Only NAK gaps that have just opened up, older ones have already been asked for
+                let newest = self.buffered.keys().map(|s| ahead_of(*s) + 1).max().unwrap_or(0);
+                for missing in newest..ahead {
+                    self.nak(((expected + missing) % 64) as u8)?;
+                }
+                self.buffered.insert(packet.seq, packet);
+            } else if let Some(ack) = self.acks.get(&packet.seq) {
+                // A packet we've already handled, our ACK must have been lost
+                let ack = ack.clone();
+                self.link.write_all(&ack)?;
+            }
+        }
+    }
+}
+
+/// Receives a batch of files in to `dir` until the sender sends a break.
+///
+/// * `seven_bit` - The port has less than 8 data bits, so 8th bit prefixing is asked for.
+pub fn receive(link: &mut Link, dir: &Path, seven_bit: bool) -> Result<u64, TransferError> {
+    let mut receiver = Receiver {
+        link,
+        params: Params::initial(seven_bit),
+        expected: 0,
+        buffered: BTreeMap::new(),
+        acks: BTreeMap::new(),
+    };
+    let mut progress = Progress::new("kermit", None);
+
+    // Nudge the sender in case it is waiting on us, then wait for its Send-Init
+    let mut retries = 0;
+    let init = loop {
+        match read_packet(receiver.link, &receiver.params, PACKET_TIMEOUT)? {
+            Read::Packet(p) if p.kind == SEND_INIT => break p,
+            Read::Packet(p) if p.kind == ERROR => return Err(remote_error(&p)),
+            _ => {
+                retries += 1;
+                if retries >= MAX_RETRIES * 2 { return Err(TransferError::TimedOut) }
+                receiver.nak(0)?;
+            }
+        }
+    };
+    receiver.ack(init.seq, &Params::ours(seven_bit))?;
+    receiver.params = Params::negotiate(seven_bit, &init.data);
+    receiver.expected = (init.seq + 1) % 64;
+
+    let mut received = 0u64;
+    let mut file: Option<(File, FileInfo)> = None;
+    loop {
+        let packet = receiver.next(&mut progress)?;
+        let params = receiver.params;
+        match packet.kind {
+            FILE_HEADER => {
+                let name = decode(&packet.data, &params);
+                let mut block = name.clone();
+                block.push(0);
+                let info = match FileInfo::from_bytes(&block)? {
+                    Some(v) => v,
+                    None => return Err(TransferError::Protocol("empty file name")),
+                };
+                let f = File::create(dir.join(&info.name)).map_err(TransferError::File)?;
+                progress = Progress::new(&format!("kermit {}", info.name), None);
+                receiver.ack(packet.seq, info.name.as_bytes())?;
+                file = Some((f, info));
+            },
+            ATTRIBUTES => {
+                progress = Progress::new(&progress_name(&file), attribute_size(&packet.data));
+                receiver.ack(packet.seq, b"Y")?;
+            },
+            DATA => {
+                let data = decode(&packet.data, &params);
+                match file.as_mut() {
+                    Some((f, _)) => f.write_all(&data).map_err(TransferError::File)?,
+                    None => return Err(TransferError::Protocol("data before file header")),
+                }
+                received += data.len() as u64;
+                progress.add_bytes(data.len() as u64);
+            },
+            END_OF_FILE => {
+                if let Some((f, info)) = file.take() {
+                    drop(f);
+                    if packet.data.first() == Some(&b'D') {
+                        let _ = std::fs::remove_file(dir.join(&info.name));
+                    }
+                }
+                receiver.ack(packet.seq, &[])?;
+                println!("\r");
+            },
+            BREAK => {
+                receiver.ack(packet.seq, &[])?;
+                return Ok(received);
+            },
+            SEND_INIT => receiver.ack(packet.seq, &Params::ours(seven_bit))?,
+            _ => receiver.ack(packet.seq, &[])?,
+        }
+    }
+}
+
+/// The name shown on the progress line for the file being received.
+fn progress_name(file: &Option<(File, FileInfo)>) -> String {
+    match file {
+        Some((_, info)) => format!("kermit {}", info.name),
+        None => "kermit".to_string(),
+    }
+}
+
+/// Reads the file size out of an attribute packet, from either the length in bytes (`1`) or
+/// in kilobytes (`!`).
+fn attribute_size(data: &[u8]) -> Option<u64> {
+    let mut i = 0;
+    let mut size = None;
+    while i + 1 < data.len() {
+        let len = unchar(data[i + 1]);
+        let value = data.get(i + 2..i + 2 + len)?;
+        let value = str::from_utf8(value).ok().and_then(|v| v.trim().parse::<u64>().ok());
+        match data[i] {
+            b'1' => size = value.or(size),
+            b'!' if size.is_none() => size = value.map(|v| v * 1024),
+            _ => (),
+        }
+        i += 2 + len;
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::testlink::{self, run, temp_dir};
+
+    /// The settings with every kind of prefixing in use.
+    fn prefixed(check: u8) -> Params {
+        Params { check, qbin: Some(QBIN), rept: Some(REPT), max_data: MAXLX - 10, window: 1, attributes: false, mask: 0xFF }
+    }
+
+    /// Control, 8 bit and prefix characters, with runs shorter and longer than a repeat count can hold.
+    fn awkward_data() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend(b"##&&~~#&~");
+        data.extend([b'a'; 10]);
+        data.extend([0x00; 200]);
+        data.extend([0xFF; 3]);
+        data.extend([0x8D, 0xA3, 0xFE, 0x7F, 0x7F]);
+        data
+    }
+
+    #[test]
+    fn block_checks() {
+        assert_eq!(block_check(1, b"123456789"), b"@");
+        assert_eq!(block_check(2, b"123456789"), b"'=");
+        // CRC-16/KERMIT of the check string is 0x2189
+        assert_eq!(block_check(3, b"123456789"), b"\"&)");
+        assert_eq!(block_check(3, b""), b"   ");
+    }
+
+    #[test]
+    fn prefixing_round_trip() {
+        let data = awkward_data();
+        let params = [
+            prefixed(3),
+            Params { qbin: None, ..prefixed(3) },
+            Params { rept: None, ..prefixed(3) },
+            Params::initial(false),
+        ];
+        for params in params {
+            let (encoded, used) = encode(&data, data.len() * 4, &params);
+            assert_eq!(used, data.len());
+            assert!(encoded.iter().all(|c| (32..127).contains(c) || params.qbin.is_none() && *c >= 128));
+            assert_eq!(decode(&encoded, &params), data, "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn repeats_are_compressed() {
+        let params = prefixed(1);
+        assert_eq!(encode(&[b'x'; 10], 100, &params).0, [REPT, tochar(10), b'x']);
+        assert_eq!(encode(&[0x01; 2], 100, &params).0, [QCTL, b'A', QCTL, b'A']);
+        assert_eq!(encode(&[0x81; 3], 100, &params).0, [REPT, tochar(3), QBIN, QCTL, b'A']);
+    }
+
+    /// Data split over several packets by the space left in each still decodes back in full,
+    /// without a prefix being cut from what it prefixes.
+    #[test]
+    fn split_encoding() {
+        let data = awkward_data();
+        let params = prefixed(1);
+        let mut out = vec![];
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let (encoded, used) = encode(rest, 7, &params);
+            assert!(used > 0 && encoded.len() <= 7);
+            out.extend(decode(&encoded, &params));
+            rest = &rest[used..];
+        }
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        for check in 1..=3 {
+            let params = prefixed(check);
+            for len in [0, 10, MAXL - 5, 1000] {
+                let data: Vec<u8> = (0..len).map(|v| b'!' + (v % 90) as u8).collect();
+                let packet = build_packet(42, DATA, &data, check);
+                let (a, b) = testlink::pipe();
+                a.send(&packet);
+                match testlink::with_link(b, |link| read_packet(link, &params, PACKET_TIMEOUT)).unwrap() {
+                    Read::Packet(p) => assert_eq!((p.seq, p.kind, p.data), (42, DATA, data)),
+                    _ => panic!("packet of {} bytes with check {} not read", len, check),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_packet() {
+        let mut packet = build_packet(1, DATA, b"hello", 3);
+        packet[5] ^= 0x01;
+        let (a, b) = testlink::pipe();
+        a.send(&packet);
+        let read = testlink::with_link(b, |link| read_packet(link, &prefixed(3), PACKET_TIMEOUT)).unwrap();
+        assert!(matches!(read, Read::Bad));
+    }
+
+    /// Sends a batch of files with the sender and receiver against each other, with and without
+    /// 8th bit prefixing.
+    #[test]
+    fn batch_round_trip() {
+        for seven_bit in [false, true] {
+            let files = [awkward_data(), (0..20000u32).map(|v| (v * 13) as u8).collect(), vec![]];
+            let dir = temp_dir(&format!("kermit-{}", seven_bit));
+            let paths: Vec<String> = files.iter().enumerate().map(|(i, data)| {
+                let path = dir.join(format!("{}.bin", i));
+                std::fs::write(&path, data).unwrap();
+                path.to_string_lossy().to_string()
+            }).collect();
+            let rx = temp_dir(&format!("kermit-{}-rx", seven_bit));
+            let rx_dir = rx.clone();
+            let (sent, received) = run(
+                move |link| send(link, &paths, seven_bit),
+                move |link| receive(link, &rx_dir, seven_bit),
+            );
+            let total = files.iter().map(|v| v.len() as u64).sum::<u64>();
+            assert_eq!(sent.unwrap(), total);
+            assert_eq!(received.unwrap(), total);
+            for (i, data) in files.iter().enumerate() {
+                assert_eq!(&std::fs::read(rx.join(format!("{}.bin", i))).unwrap(), data);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::serialstream::SerialStream;
+use super::Link;
+
+
+/// The bytes on their way in one direction along the line.
+type Buffer = Arc<(Mutex<VecDeque<u8>>, Condvar)>;
+
+/// One end of an in memory serial line, what's written to one end is read from the other.
+#[derive(Clone)]
+pub struct Pipe {
+    /// The bytes sent by the other end.
+    rx: Buffer,
+    /// The bytes sent to the other end.
+    tx: Buffer,
+    /// How long a read waits for something to arrive.
+    timeout: Duration,
+}
+
+/// Creates both ends of a line.
+pub fn pipe() -> (Pipe, Pipe) {
+    let (a, b): (Buffer, Buffer) = (Arc::default(), Arc::default());
+    let timeout = Duration::from_millis(100);
+    (Pipe { rx: a.clone(), tx: b.clone(), timeout }, Pipe { rx: b, tx: a, timeout })
+}
+
+impl Pipe {
+    /// Starts a stream reading what arrives at this end.
+    pub fn stream(&self) -> SerialStream {
+        let (waker, _) = mpsc::channel();
+        SerialStream::new(Box::new(self.clone()), waker)
+    }
+
+    /// Sends bytes to the other end, as if the remote had sent them.
+    pub fn send(&self, data: &[u8]) {
+        let (lock, ready) = &*self.tx;
+        lock.lock().unwrap().extend(data);
+        ready.notify_all();
+    }
+}
+
+/// Runs the two sides of a transfer against each other, each on its own thread with its own link,
+/// returning what each gave back.
+pub fn run<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce(&mut Link) -> RA + Send + 'static,
+    B: FnOnce(&mut Link) -> RB + Send + 'static,
+    RA: Send + 'static,
+    RB: Send + 'static,
+{
+    let (pa, pb) = pipe();
+    let a = thread::spawn(move || with_link(pa, a));
+    let b = thread::spawn(move || with_link(pb, b));
+    (a.join().unwrap(), b.join().unwrap())
+}
+
+/// Gives a link over one end of a line to `f`.
+pub fn with_link<R>(mut port: Pipe, f: impl FnOnce(&mut Link) -> R) -> R {
+    let mut serial = port.stream();
+    let mut link = Link::new(&mut port, &mut serial, None, 0x01, None, false);
+    f(&mut link)
+}
+
+/// Creates an empty directory for a test to put files in.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nanocom-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+impl io::Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (lock, ready) = &*self.rx;
+        let deadline = Instant::now() + self.timeout;
+        let mut data = lock.lock().unwrap();
+        while data.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() { return Err(io::Error::from(io::ErrorKind::TimedOut)) }
+            data = ready.wait_timeout(data, remaining).unwrap().0;
+        }
+        let n = buf.len().min(data.len());
+        for (b, v) in buf.iter_mut().zip(data.drain(..n)) { *b = v }
+        Ok(n)
+    }
+}
+
+impl io::Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for Pipe {
+    fn name(&self) -> Option<String> { Some("pipe".to_string()) }
+    fn baud_rate(&self) -> serialport::Result<u32> { Ok(115200) }
+    fn data_bits(&self) -> serialport::Result<DataBits> { Ok(DataBits::Eight) }
+    fn flow_control(&self) -> serialport::Result<FlowControl> { Ok(FlowControl::None) }
+    fn parity(&self) -> serialport::Result<Parity> { Ok(Parity::None) }
+    fn stop_bits(&self) -> serialport::Result<StopBits> { Ok(StopBits::One) }
+    fn timeout(&self) -> Duration { self.timeout }
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> { Ok(()) }
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> { Ok(()) }
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> { Ok(()) }
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> { Ok(()) }
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> { Ok(()) }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> { Ok(()) }
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> { Ok(()) }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> { Ok(true) }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> { Ok(true) }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> { Ok(false) }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> { Ok(true) }
+    fn bytes_to_read(&self) -> serialport::Result<u32> { Ok(self.rx.0.lock().unwrap().len() as u32) }
+    fn bytes_to_write(&self) -> serialport::Result<u32> { Ok(0) }
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> { Ok(()) }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> { Ok(Box::new(self.clone())) }
+    fn set_break(&self) -> serialport::Result<()> { Ok(()) }
+    fn clear_break(&self) -> serialport::Result<()> { Ok(()) }
+}
@@ -0,0 +1,395 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use super::{crc16, Link, Progress, TransferError, CAN};
+
+
+/// Start of a 128 byte block.
+pub const SOH: u8 = 0x01;
+/// Start of a 1024 byte block.
+pub const STX: u8 = 0x02;
+/// End of transmission.
+pub const EOT: u8 = 0x04;
+/// Positive acknowledgement.
+pub const ACK: u8 = 0x06;
+/// Negative acknowledgement, also asks the sender to start using checksums.
+pub const NAK: u8 = 0x15;
+/// Asks the sender to start using CRC-16.
+pub const CRC: u8 = b'C';
+/// Used to pad out the last block of a file.
+pub const SUB: u8 = 0x1A;
+
+/// How many times a block is attempted before giving up.
+pub const MAX_RETRIES: u32 = 10;
+/// How long the sender waits for the receiver to start the transfer.
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the receiver waits between sending start requests.
+const START_INTERVAL: Duration = Duration::from_secs(3);
+/// How many start requests the receiver sends asking for CRC-16 before falling back to checksums.
+const CRC_ATTEMPTS: u32 = 3;
+/// How many start requests the receiver sends before giving up.
+const START_ATTEMPTS: u32 = 20;
+/// How long the line must be quiet after a start request before the sender starts.
+const START_QUIET: Duration = Duration::from_millis(100);
+/// How long to wait for an acknowledgement or the next block.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait between bytes within a block.
+pub const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The type of error detection used on each block, this is chosen by the receiver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// 8 bit arithmetic sum of the data.
+    Checksum,
+    /// CRC-16/XMODEM of the data.
+    Crc,
+}
+
+impl Check {
+    /// The number of bytes the check takes up at the end of a block.
+    pub fn size(&self) -> usize {
+        match self {
+            Check::Checksum => 1,
+            Check::Crc => 2,
+        }
+    }
+
+    /// Calculates the check bytes for a block of data.
+    pub fn calculate(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Check::Checksum => vec![data.iter().fold(0u8, |a, b| a.wrapping_add(*b))],
+            Check::Crc => crc16(data).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// The size of the blocks the sender will try to use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockSize {
+    /// 128 byte blocks (SOH).
+    Short,
+    /// 1024 byte blocks (STX), only used if the receiver asks for CRC-16.
+    Long,
+}
+
+impl BlockSize {
+    /// The number of data bytes in a block.
+    pub fn size(&self) -> usize {
+        match self {
+            BlockSize::Short => 128,
+            BlockSize::Long => 1024,
+        }
+    }
+}
+
+/// A block read by the receiver.
+#[derive(Debug)]
+pub enum Block {
+    /// A valid block with its sequence number and data.
+    Data(u8, Vec<u8>),
+    /// The sender has finished.
+    Eot,
+    /// A block was received but was corrupted or incomplete.
+    Bad,
+    /// Nothing was received.
+    Timeout,
+}
+
+/// Sends a file, waiting for the receiver to start the transfer.
+///
+/// Long blocks are only used if the receiver asks for CRC-16, and the tail of the file is sent
+/// in short blocks if it fits.
+pub fn send(link: &mut Link, file: &mut impl Read, size: BlockSize, progress: &mut Progress) -> Result<u64, TransferError> {
+    let check = wait_for_start(link)?;
+    let size = if check == Check::Checksum { BlockSize::Short } else { size };
+    let sent = send_data(link, file, size, check, 1, progress)?;
+    send_eot(link, progress)?;
+    Ok(sent)
+}
+
+/// Sends the contents of a file as a run of blocks starting at sequence number `first`, this does
+/// not send the EOT.
+pub fn send_data(link: &mut Link,
+    file: &mut impl Read,
+    size: BlockSize,
+    check: Check,
+    first: u8,
+    progress: &mut Progress) -> Result<u64, TransferError>
+{
+    let mut buf = vec![0u8; size.size()];
+    let mut num = first;
+    let mut sent = 0u64;
+    loop {
+        let n = read_full(file, &mut buf)?;
+        if n == 0 { return Ok(sent) }
+        let len = if n <= BlockSize::Short.size() { BlockSize::Short.size() } else { size.size() };
+        send_block(link, num, &buf[..n], len, check, progress)?;
+        num = num.wrapping_add(1);
+        sent += n as u64;
+        progress.add_bytes(n as u64);
+    }
+}
+
+/// Waits for the receiver to send a start request, returning the check it asked for.
+///
+/// Any more start requests already sent are discarded, as they'd be taken as NAKs of the first block.
+pub fn wait_for_start(link: &mut Link) -> Result<Check, TransferError> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    let mut last = 0u8;
+    let check = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match link.read_byte(remaining)? {
+            Some(NAK) => break Check::Checksum,
+            Some(CRC) => break Check::Crc,
+            Some(CAN) if last == CAN => return Err(TransferError::RemoteCancelled),
+            Some(v) => last = v,
+            None => return Err(TransferError::TimedOut),
+        }
+    };
+    link.purge(START_QUIET)?;
+    Ok(check)
+}
+
+/// Sends a single block, padding the data out to `len` bytes, and retrying until it is acknowledged.
+pub fn send_block(link: &mut Link,
+    num: u8,
+    data: &[u8],
+    len: usize,
+    check: Check,
+    progress: &mut Progress) -> Result<(), TransferError>
+{
+    let mut packet = Vec::with_capacity(len + 5);
+    packet.push(if len == BlockSize::Long.size() { STX } else { SOH });
+    packet.push(num);
+    packet.push(!num);
+    packet.extend_from_slice(data);
+    packet.resize(len + 3, SUB);
+    let check_bytes = check.calculate(&packet[3..]);
+    packet.extend(check_bytes);
+
+    for _ in 0..MAX_RETRIES {
+        link.write_all(&packet)?;
+        if let Some(ACK) = wait_for_response(link)? { return Ok(()) }
+        progress.add_retry();
+    }
+    Err(TransferError::TooManyRetries)
+}
+
+/// Sends the end of transmission, retrying until it is acknowledged.
+pub fn send_eot(link: &mut Link, progress: &mut Progress) -> Result<(), TransferError> {
+    for _ in 0..MAX_RETRIES {
+        link.write_all(&[EOT])?;
+        if let Some(ACK) = wait_for_response(link)? { return Ok(()) }
+        progress.add_retry();
+    }
+    Err(TransferError::TooManyRetries)
+}
+
+/// Waits for an ACK or NAK from the receiver, anything else is ignored, a start request is
+/// treated as a NAK. Returns `None` if nothing was received in time.
+fn wait_for_response(link: &mut Link) -> Result<Option<u8>, TransferError> {
+    let mut last = 0u8;
+    loop {
+        match link.read_byte(BLOCK_TIMEOUT)? {
+            Some(ACK) => return Ok(Some(ACK)),
+            Some(NAK) | Some(CRC) => return Ok(Some(NAK)),
+            Some(CAN) if last == CAN => return Err(TransferError::RemoteCancelled),
+            Some(v) => last = v,
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Receives a file, starting the transfer by asking for the given check, if CRC-16 is asked for
+/// but the sender doesn't respond, this falls back to checksums.
+pub fn receive(link: &mut Link, file: &mut impl Write, check: Check, progress: &mut Progress) -> Result<u64, TransferError> {
+    let (check, first) = start_receive(link, check, true)?;
+    receive_data(link, file, check, first, 1, progress)
+}
+
+/// Receives a run of blocks in to the file until the sender sends an EOT, expecting the first
+/// block to have the sequence number `expected`.
+///
+/// * `first` - The first block, as returned from [start_receive].
+pub fn receive_data(link: &mut Link,
+    file: &mut impl Write,
+    check: Check,
+    first: Block,
+    expected: u8,
+    progress: &mut Progress) -> Result<u64, TransferError>
+{
+    let mut block = first;
+    let mut expected = expected;
+    let mut received = 0u64;
+    let mut errors = 0u32;
+    loop {
+        match block {
+            Block::Data(num, data) if num == expected => {
+                file.write_all(&data).map_err(TransferError::File)?;
+                link.write_all(&[ACK])?;
+                expected = expected.wrapping_add(1);
+                received += data.len() as u64;
+                errors = 0;
+                progress.add_bytes(data.len() as u64);
+            },
+            Block::Data(num, _) if num == expected.wrapping_sub(1) => link.write_all(&[ACK])?,
+            Block::Data(..) => return Err(TransferError::Protocol("block out of sequence")),
+            Block::Eot => {
+                link.write_all(&[ACK])?;
+                return Ok(received);
+            },
+            Block::Bad | Block::Timeout => {
+                errors += 1;
+                if errors >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+                progress.add_retry();
+                link.purge(BYTE_TIMEOUT)?;
+                link.write_all(&[NAK])?;
+            },
+        }
+        block = read_block(link, check, BLOCK_TIMEOUT)?;
+    }
+}
+
+/// Sends start requests until the sender responds with the first block (or EOT), returning the
+/// check that was settled on along with the block.
+///
+/// * `fallback` - If true, and CRC-16 was asked for, switch to asking for checksums after a few
+///   unanswered requests.
+pub fn start_receive(link: &mut Link, check: Check, fallback: bool) -> Result<(Check, Block), TransferError> {
+    let mut check = check;
+    for attempt in 0..START_ATTEMPTS {
+        if fallback && check == Check::Crc && attempt == CRC_ATTEMPTS {
+            check = Check::Checksum;
+        }
+        link.write_all(&[if check == Check::Crc { CRC } else { NAK }])?;
+        match read_block(link, check, START_INTERVAL)? {
+            Block::Timeout => continue,
+            Block::Bad => link.purge(BYTE_TIMEOUT)?,
+            block => return Ok((check, block)),
+        }
+    }
+    Err(TransferError::TimedOut)
+}
+
+/// Reads a single block from the sender, skipping any noise before the block header.
+pub fn read_block(link: &mut Link, check: Check, timeout: Duration) -> Result<Block, TransferError> {
+    let mut last = 0u8;
+    let len = loop {
+        match link.read_byte(timeout)? {
+            Some(SOH) => break BlockSize::Short.size(),
+            Some(STX) => break BlockSize::Long.size(),
+            Some(EOT) => return Ok(Block::Eot),
+            Some(CAN) if last == CAN => return Err(TransferError::RemoteCancelled),
+            Some(v) => last = v,
+            None => return Ok(Block::Timeout),
+        }
+    };
+
+    let mut buf = vec![0u8; len + 2 + check.size()];
+    if !link.read_exact(&mut buf, BYTE_TIMEOUT)? { return Ok(Block::Bad) }
+    let (num, inv) = (buf[0], buf[1]);
+    let data = &buf[2..len + 2];
+    if num != !inv || buf[len + 2..] != check.calculate(data)[..] {
+        return Ok(Block::Bad);
+    }
+    Ok(Block::Data(num, data.to_vec()))
+}
+
+/// Reads from the file until the buffer is full or the end of the file, returning the number of
+/// bytes read.
+pub fn read_full(file: &mut impl Read, buf: &mut [u8]) -> Result<usize, TransferError> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(TransferError::File(e)),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::testlink::{self, run};
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(Check::Checksum.calculate(&[0xFF, 0x02]), vec![0x01]);
+    }
+
+    /// Sends a block of each size with each check and reads it back on the other end.
+    #[test]
+    fn blocks_round_trip() {
+        for (len, check) in [(128, Check::Checksum), (128, Check::Crc), (1024, Check::Crc)] {
+            let data: Vec<u8> = (0..len as u32 - 10).map(|v| (v * 7) as u8).collect();
+            let sent = data.clone();
+            let (_, block) = run(
+                move |link| send_block(link, 3, &sent, len, check, &mut Progress::new("test", None)),
+                move |link| {
+                    let block = read_block(link, check, BLOCK_TIMEOUT);
+                    link.write_all(&[ACK]).unwrap();
+                    block
+                },
+            );
+            let mut padded = data;
+            padded.resize(len, SUB);
+            assert!(matches!(block, Ok(Block::Data(3, v)) if v == padded));
+        }
+    }
+
+    /// Corrupts a byte of a block, which should be read as bad rather than as data.
+    #[test]
+    fn corrupt_block_is_bad() {
+        let (a, b) = testlink::pipe();
+        let mut packet = vec![SOH, 1, !1];
+        packet.extend([0x55u8; 128]);
+        packet.extend(crc16(&[0x55; 128]).to_be_bytes());
+        packet[40] ^= 0x01;
+        a.send(&packet);
+        let block = testlink::with_link(b, |link| read_block(link, Check::Crc, BLOCK_TIMEOUT));
+        assert!(matches!(block, Ok(Block::Bad)));
+    }
+
+    /// Sends a file and receives it, including a tail short enough to go in a short block.
+    #[test]
+    fn file_round_trip() {
+        let data: Vec<u8> = (0..3000u32).map(|v| (v % 251) as u8).collect();
+        let sent = data.clone();
+        let (sent_len, received) = run(
+            move |link| send(link, &mut sent.as_slice(), BlockSize::Long, &mut Progress::new("test", None)),
+            move |link| {
+                let mut file = Vec::new();
+                receive(link, &mut file, Check::Crc, &mut Progress::new("test", None)).map(|_| file)
+            },
+        );
+        assert_eq!(sent_len.unwrap(), 3000);
+        let received = received.unwrap();
+        assert_eq!(&received[..3000], &data[..]);
+        assert!(received[3000..].iter().all(|b| *b == SUB));
+    }
+
+    /// Start requests queued up before the sender starts shouldn't be taken as NAKs of block 1.
+    #[test]
+    fn queued_start_requests_are_drained() {
+        let (a, b) = testlink::pipe();
+        b.send(b"CCC");
+        let sender = std::thread::spawn(move || testlink::with_link(a, |link| {
+            send(link, &mut [0x42u8; 100].as_slice(), BlockSize::Short, &mut Progress::new("test", None))
+        }));
+        testlink::with_link(b, |link| {
+            assert!(matches!(read_block(link, Check::Crc, BLOCK_TIMEOUT), Ok(Block::Data(1, _))));
+            link.write_all(&[ACK]).unwrap();
+            assert!(matches!(read_block(link, Check::Crc, BLOCK_TIMEOUT), Ok(Block::Eot)));
+            link.write_all(&[ACK]).unwrap();
+        });
+        assert_eq!(sender.join().unwrap().unwrap(), 100);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::xmodem::{self, Block, BlockSize, Check, ACK};
+use super::{Link, Progress, TransferError};
+
+
+/// The metadata sent in block 0 ahead of each file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// The name of the file, without any directories.
+    pub name: String,
+    /// The size of the file in bytes, if the sender gave it.
+    pub size: Option<u64>,
+    /// The modification time in seconds since the unix epoch, if the sender gave it.
+    pub mtime: Option<u64>,
+}
+
+impl FileInfo {
+
+    /// Reads the metadata from a local file to send.
+    pub fn from_file(path: &Path, file: &File) -> Result<FileInfo, TransferError> {
+        let name = match path.file_name() {
+            Some(v) => v.to_string_lossy().to_string(),
+            None => return Err(TransferError::Unsupported("can't send a path without a file name"))
+        };
+        let meta = file.metadata().map_err(TransferError::File)?;
+        let mtime = meta.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        Ok(FileInfo { name, size: Some(meta.len()), mtime })
+    }
+
+    /// Serializes to the contents of block 0: the name, a NUL, then the size in decimal and the
+    /// modification time in octal separated by a space.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = self.name.as_bytes().to_vec();
+        res.push(0);
+        if let Some(size) = self.size {
+            res.extend(size.to_string().bytes());
+            if let Some(mtime) = self.mtime {
+                res.extend(format!(" {:o}", mtime).bytes());
+            }
+        }
+        res.push(0);
+        res
+    }
+
+    /// Parses the contents of block 0, returning `None` for the empty block that ends a batch.
+    ///
+    /// Any directories in the name are dropped so the file can only be written in to the
+    /// receive directory.
+    pub fn from_bytes(data: &[u8]) -> Result<Option<FileInfo>, TransferError> {
+        if data.first().is_none_or(|b| *b == 0) { return Ok(None) }
+        let mut fields = data.split(|b| *b == 0);
+        let name = String::from_utf8_lossy(fields.next().unwrap_or_default());
+        let name = match Path::new(name.as_ref()).file_name() {
+            Some(v) => v.to_string_lossy().to_string(),
+            None => return Err(TransferError::Protocol("invalid file name"))
+        };
+        let meta = String::from_utf8_lossy(fields.next().unwrap_or_default());
+        let mut meta = meta.split_whitespace();
+        let size = meta.next().and_then(|v| v.parse::<u64>().ok());
+        let mtime = meta.next().and_then(|v| u64::from_str_radix(v, 8).ok());
+        Ok(Some(FileInfo { name, size, mtime }))
+    }
+}
+
+/// Sends a batch of files, each preceded by its metadata, followed by an empty block 0 to end
+/// the batch.
+pub fn send(link: &mut Link, paths: &[String]) -> Result<u64, TransferError> {
+    let mut sent = 0u64;
+    for path in paths {
+        let path = Path::new(path);
+        let mut file = File::open(path).map_err(TransferError::File)?;
+        let info = FileInfo::from_file(path, &file)?;
+        let mut progress = Progress::new(&format!("ymodem {}", info.name), info.size);
+
+        let check = xmodem::wait_for_start(link)?;
+        send_header(link, &info.to_bytes(), check, &mut progress)?;
+        let check = xmodem::wait_for_start(link)?;
+        let size = if check == Check::Crc { BlockSize::Long } else { BlockSize::Short };
+        sent += xmodem::send_data(link, &mut file, size, check, 1, &mut progress)?;
+        xmodem::send_eot(link, &mut progress)?;
+        println!("\r");
+    }
+
+    let mut progress = Progress::new("ymodem", None);
+    let check = xmodem::wait_for_start(link)?;
+    send_header(link, &[], check, &mut progress)?;
+    Ok(sent)
+}
+
+/// Sends block 0 padded with NULs, using a long block if the metadata doesn't fit in a short one.
+fn send_header(link: &mut Link, data: &[u8], check: Check, progress: &mut Progress) -> Result<(), TransferError> {
+    let len = if data.len() > BlockSize::Short.size() { BlockSize::Long } else { BlockSize::Short };
+    let mut block = data.to_vec();
+    block.resize(len.size(), 0);
+    xmodem::send_block(link, 0, &block, len.size(), check, progress)
+}
+
+/// Receives a batch of files in to `dir`, until the sender ends the batch.
+///
+/// Files are truncated to the size given by the sender, to remove the padding from the last
+/// block, and given the sender's modification time.
+pub fn receive(link: &mut Link, dir: &Path) -> Result<u64, TransferError> {
+    let mut received = 0u64;
+    loop {
+        let info = match xmodem::start_receive(link, Check::Crc, false)? {
+            (_, Block::Data(0, data)) => FileInfo::from_bytes(&data)?,
+            _ => return Err(TransferError::Protocol("expected file header")),
+        };
+        link.write_all(&[ACK])?;
+        let info = match info {
+            Some(v) => v,
+            None => return Ok(received),
+        };
+
+        let path = dir.join(&info.name);
+        let mut file = File::create(&path).map_err(TransferError::File)?;
+        let mut progress = Progress::new(&format!("ymodem {}", info.name), info.size);
+        let (check, first) = xmodem::start_receive(link, Check::Crc, false)?;
+        let written = xmodem::receive_data(link, &mut file, check, first, 1, &mut progress)?;
+
+        if let Some(size) = info.size.filter(|s| *s < written) {
+            file.set_len(size).map_err(TransferError::File)?;
+        }
+        if let Some(mtime) = info.mtime {
+            let _ = file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+        }
+        received += info.size.unwrap_or(written).min(written);
+        println!("\r");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let info = FileInfo { name: "data.bin".to_string(), size: Some(123456), mtime: Some(0o14712345670) };
+        let bytes = info.to_bytes();
+        assert_eq!(bytes, b"data.bin\x00123456 14712345670\x00");
+        assert_eq!(FileInfo::from_bytes(&bytes).unwrap(), Some(info));
+    }
+
+    #[test]
+    fn header_padded_with_nuls() {
+        let info = FileInfo { name: "a.txt".to_string(), size: Some(10), mtime: None };
+        let mut bytes = info.to_bytes();
+        bytes.resize(BlockSize::Short.size(), 0);
+        assert_eq!(FileInfo::from_bytes(&bytes).unwrap(), Some(info));
+    }
+
+    /// The size is optional, and the modification time can only be given after it.
+    #[test]
+    fn header_without_size() {
+        let info = FileInfo { name: "a.txt".to_string(), size: None, mtime: Some(100) };
+        let bytes = info.to_bytes();
+        assert_eq!(bytes, b"a.txt\x00\x00");
+        let info = FileInfo { name: "a.txt".to_string(), size: None, mtime: None };
+        assert_eq!(FileInfo::from_bytes(&bytes).unwrap(), Some(info.clone()));
+        assert_eq!(FileInfo::from_bytes(b"a.txt").unwrap(), Some(info));
+    }
+
+    #[test]
+    fn header_drops_directories() {
+        let info = FileInfo::from_bytes(b"../../etc/passwd\x0012\x00").unwrap().unwrap();
+        assert_eq!(info.name, "passwd");
+        assert_eq!(info.size, Some(12));
+        assert!(FileInfo::from_bytes(b"..\x00").is_err());
+    }
+
+    /// An empty block 0 ends the batch.
+    #[test]
+    fn end_of_batch() {
+        assert_eq!(FileInfo::from_bytes(&[0; 128]).unwrap(), None);
+        assert_eq!(FileInfo::from_bytes(&[]).unwrap(), None);
+    }
+}
@@ -0,0 +1,1018 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serialport::FlowControl;
+
+use super::xmodem;
+use super::ymodem::FileInfo;
+use super::{crc16, crc32, Link, Progress, TransferError, CAN};
+
+
+/// Frame padding, starts every header.
+pub const ZPAD: u8 = b'*';
+/// The escape byte, the same as CAN.
+pub const ZDLE: u8 = CAN;
+/// Binary header with a CRC-16.
+const ZBIN: u8 = b'A';
+/// Hex header with a CRC-16.
+const ZHEX: u8 = b'B';
+/// Binary header with a CRC-32.
+const ZBIN32: u8 = b'C';
+/// Software flow control bytes, never sent unescaped and ignored when received.
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Header types.
+pub const ZRQINIT: u8 = 0;
+pub const ZRINIT: u8 = 1;
+pub const ZSINIT: u8 = 2;
+pub const ZACK: u8 = 3;
+pub const ZFILE: u8 = 4;
+pub const ZSKIP: u8 = 5;
+pub const ZNAK: u8 = 6;
+pub const ZABORT: u8 = 7;
+pub const ZFIN: u8 = 8;
+pub const ZRPOS: u8 = 9;
+pub const ZDATA: u8 = 10;
+pub const ZEOF: u8 = 11;
+pub const ZFERR: u8 = 12;
+pub const ZCRC: u8 = 13;
+pub const ZCHALLENGE: u8 = 14;
+pub const ZCAN: u8 = 16;
+pub const ZCOMMAND: u8 = 18;
+
+/// Data subpacket terminators.
+///
+/// * `ZCRCE` - End of frame, a header follows.
+/// * `ZCRCG` - More data follows, no response expected.
+/// * `ZCRCQ` - More data follows, the receiver responds with a ZACK.
+/// * `ZCRCW` - End of frame, the receiver responds with a ZACK.
+pub const ZCRCE: u8 = b'h';
+pub const ZCRCG: u8 = b'i';
+pub const ZCRCQ: u8 = b'j';
+pub const ZCRCW: u8 = b'k';
+/// Escaped forms of `0x7F` and `0xFF`.
+const ZRUB0: u8 = b'l';
+const ZRUB1: u8 = b'm';
+
+/// ZRINIT capability flags (ZF0).
+const CANFDX: u8 = 0x01;
+const CANOVIO: u8 = 0x02;
+const CANFC32: u8 = 0x20;
+const ESCCTL: u8 = 0x40;
+
+/// ZFILE conversion options (ZF0).
+const ZCBIN: u8 = 1;
+const ZCRESUM: u8 = 3;
+
+/// The string sent ahead of ZRQINIT to start `rz` on the remote.
+const RZ_COMMAND: &[u8] = b"rz\r";
+/// The start of a ZRQINIT hex header, used to spot an incoming send in the terminal.
+pub const ZRQINIT_SEQ: &[u8] = b"**\x18B00";
+
+/// How many times a header is resent before giving up.
+const MAX_RETRIES: u32 = 10;
+/// How long to wait for a header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait between bytes within a header or subpacket.
+const BYTE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How much noise is skipped looking for a header before treating it as a bad header.
+const MAX_GARBAGE: usize = 8192;
+/// The largest data subpacket accepted.
+const MAX_SUBPACKET: usize = 8192;
+/// The size of the data subpackets sent.
+const SUBPACKET_SIZE: usize = 1024;
+/// How much data can go unacknowledged when there is no flow control on the port.
+const WINDOW: u64 = 16384;
+
+/// A ZMODEM header, the 4 data bytes are either a little endian file position or flags (ZF3..ZF0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// The header type.
+    pub kind: u8,
+    /// The position or flags.
+    pub data: [u8; 4],
+}
+
+impl Header {
+    /// Creates a header carrying a file position.
+    pub fn pos(kind: u8, pos: u64) -> Header {
+        Header { kind, data: (pos as u32).to_le_bytes() }
+    }
+
+    /// Creates a header carrying flags in ZF0.
+    pub fn flags(kind: u8, zf0: u8) -> Header {
+        Header { kind, data: [0, 0, 0, zf0] }
+    }
+
+    /// Reads the data as a file position.
+    pub fn position(&self) -> u64 {
+        u32::from_le_bytes(self.data) as u64
+    }
+
+    /// Reads ZF0.
+    pub fn zf0(&self) -> u8 {
+        self.data[3]
+    }
+
+    /// The type and data bytes the CRC is calculated over.
+    fn bytes(&self) -> [u8; 5] {
+        [self.kind, self.data[0], self.data[1], self.data[2], self.data[3]]
+    }
+}
+
+/// Escapes data for sending, keeping track of the last byte sent.
+struct Encoder {
+    /// Use CRC-32 for binary headers and subpackets.
+    crc32: bool,
+    /// Escape all control characters, asked for by the receiver.
+    escape_ctl: bool,
+    last: u8,
+}
+
+impl Encoder {
+    fn new(crc32: bool, escape_ctl: bool) -> Encoder {
+        Encoder { crc32, escape_ctl, last: 0 }
+    }
+
+    /// Appends a byte to the output, escaping it if needed.
+    ///
+    /// ZDLE and the flow control bytes are always escaped, a CR is escaped after an `@` so
+    /// it can't be mistaken for a telnet escape.
+    fn push(&mut self, out: &mut Vec<u8>, b: u8) {
+        let escape = match b {
+            ZDLE | 0x10 | 0x90 | XON | 0x91 | XOFF | 0x93 => true,
+            0x0D | 0x8D => self.last & 0x7F == b'@',
+            _ => self.escape_ctl && b & 0x60 == 0,
+        };
+        if escape {
+            out.push(ZDLE);
+            out.push(b ^ 0x40);
+        } else {
+            out.push(b);
+        }
+        self.last = b;
+    }
+
+    fn extend(&mut self, out: &mut Vec<u8>, data: &[u8]) {
+        for b in data { self.push(out, *b) }
+    }
+
+    /// Encodes a binary header, using CRC-32 if it was agreed.
+    fn bin_header(&mut self, header: &Header) -> Vec<u8> {
+        let mut out = vec![ZPAD, ZDLE, if self.crc32 { ZBIN32 } else { ZBIN }];
+        let bytes = header.bytes();
+        self.extend(&mut out, &bytes);
+        if self.crc32 {
+            self.extend(&mut out, &crc32(&bytes).to_le_bytes());
+        } else {
+            self.extend(&mut out, &crc16(&bytes).to_be_bytes());
+        }
+        out
+    }
+
+    /// Encodes a data subpacket with its terminator and CRC.
+    fn subpacket(&mut self, data: &[u8], end: u8) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() * 2 + 12);
+        self.extend(&mut out, data);
+        out.push(ZDLE);
+        out.push(end);
+        let mut checked = data.to_vec();
+        checked.push(end);
+        if self.crc32 {
+            self.extend(&mut out, &crc32(&checked).to_le_bytes());
+        } else {
+            self.extend(&mut out, &crc16(&checked).to_be_bytes());
+        }
+        if end == ZCRCW { out.push(XON) }
+        out
+    }
+}
+
+/// Encodes a hex header, these are used for anything sent by the receiver and for ZRQINIT/ZFIN.
+pub fn hex_header(header: &Header) -> Vec<u8> {
+    let bytes = header.bytes();
+    let crc = crc16(&bytes).to_be_bytes();
+    let mut out = vec![ZPAD, ZPAD, ZDLE, ZHEX];
+    for b in bytes.iter().chain(crc.iter()) {
+        out.extend(format!("{:02x}", b).bytes());
+    }
+    out.extend([b'\r', b'\n' | 0x80]);
+    if header.kind != ZFIN && header.kind != ZACK { out.push(XON) }
+    out
+}
+
+/// A byte read after ZDLE decoding.
+enum Zdle {
+    /// A data byte.
+    Byte(u8),
+    /// A subpacket terminator.
+    End(u8),
+    /// An invalid escape sequence.
+    Bad,
+    /// Nothing was received in time.
+    Timeout,
+}
+
+/// Reads a single byte, undoing the ZDLE escaping and skipping flow control bytes.
+fn read_zdle(link: &mut Link) -> Result<Zdle, TransferError> {
+    loop {
+        let c = match link.read_byte(BYTE_TIMEOUT)? {
+            Some(v) => v,
+            None => return Ok(Zdle::Timeout)
+        };
+        match c {
+            XON | XOFF | 0x91 | 0x93 => continue,
+            ZDLE => (),
+            _ => return Ok(Zdle::Byte(c)),
+        }
+        let mut cans = 1;
+        loop {
+            let c = match link.read_byte(BYTE_TIMEOUT)? {
+                Some(v) => v,
+                None => return Ok(Zdle::Timeout)
+            };
+            return Ok(match c {
+                CAN => {
+                    cans += 1;
+                    if cans >= 5 { return Err(TransferError::RemoteCancelled) }
+                    continue;
+                },
+                _ if cans > 1 => Zdle::Bad,
+                XON | XOFF | 0x91 | 0x93 => continue,
+                ZCRCE | ZCRCG | ZCRCQ | ZCRCW => Zdle::End(c),
+                ZRUB0 => Zdle::Byte(0x7F),
+                ZRUB1 => Zdle::Byte(0xFF),
+                _ if c & 0x60 == 0x40 => Zdle::Byte(c ^ 0x40),
+                _ => Zdle::Bad,
+            });
+        }
+    }
+}
+
+/// Reads `n` ZDLE decoded bytes, returning `None` if anything other than plain bytes turns up.
+fn read_zdle_bytes(link: &mut Link, n: usize) -> Result<Option<Vec<u8>>, TransferError> {
+    let mut res = Vec::with_capacity(n);
+    while res.len() < n {
+        match read_zdle(link)? {
+            Zdle::Byte(b) => res.push(b),
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(res))
+}
+
+/// Reads the next header, skipping any noise before it. Returns the header and whether it used
+/// CRC-32 (so the data that follows does too), or `None` if nothing valid was received in time.
+pub fn read_header(link: &mut Link, timeout: Duration) -> Result<Option<(Header, bool)>, TransferError> {
+    let mut garbage = 0usize;
+    let mut cans = 0;
+    let mut last = 0u8;
+    loop {
+        let c = match link.read_byte(timeout)? {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        cans = if c == CAN { cans + 1 } else { 0 };
+        if cans >= 5 { return Err(TransferError::RemoteCancelled) }
+
+        if last == ZDLE && c != CAN {
+            let res = match c {
+                ZHEX => read_hex_header(link)?.map(|h| (h, false)),
+                ZBIN => read_bin_header(link, false)?.map(|h| (h, false)),
+                ZBIN32 => read_bin_header(link, true)?.map(|h| (h, true)),
+                _ => None,
+            };
+            if res.is_some() { return Ok(res) }
+        }
+        last = if c == ZPAD || (c == ZDLE && last == ZPAD) { c } else { 0 };
+
+        garbage += 1;
+        if garbage > MAX_GARBAGE { return Ok(None) }
+    }
+}
+
+/// Reads the body of a hex header, after the `ZPAD ZDLE ZHEX`.
+fn read_hex_header(link: &mut Link) -> Result<Option<Header>, TransferError> {
+    let mut hex = [0u8; 14];
+    if !link.read_exact(&mut hex, BYTE_TIMEOUT)? { return Ok(None) }
+    let mut bytes = [0u8; 7];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        let s = match str::from_utf8(&hex[i * 2..i * 2 + 2]) {
+            Ok(s) => s,
+            Err(_) => return Ok(None)
+        };
+        *b = match u8::from_str_radix(s, 16) {
+            Ok(v) => v,
+            Err(_) => return Ok(None)
+        };
+    }
+    if crc16(&bytes[..5]).to_be_bytes() != bytes[5..] { return Ok(None) }
+    // The CR LF after a hex header, the XON is dropped by the ZDLE reader later on
+    let _ = link.read_byte(Duration::from_millis(100))?;
+    let _ = link.read_byte(Duration::from_millis(100))?;
+    Ok(Some(Header { kind: bytes[0], data: [bytes[1], bytes[2], bytes[3], bytes[4]] }))
+}
+
+/// Reads the body of a binary header, after the `ZPAD ZDLE ZBIN/ZBIN32`.
+fn read_bin_header(link: &mut Link, crc32_used: bool) -> Result<Option<Header>, TransferError> {
+    let len = if crc32_used { 9 } else { 7 };
+    let bytes = match read_zdle_bytes(link, len)? {
+        Some(v) => v,
+        None => return Ok(None)
+    };
+    let valid = if crc32_used {
+        crc32(&bytes[..5]).to_le_bytes() == bytes[5..]
+    } else {
+        crc16(&bytes[..5]).to_be_bytes() == bytes[5..]
+    };
+    if !valid { return Ok(None) }
+    Ok(Some(Header { kind: bytes[0], data: [bytes[1], bytes[2], bytes[3], bytes[4]] }))
+}
+
+/// A data subpacket read by [read_subpacket].
+enum Subpacket {
+    /// Valid data with its terminator.
+    Data(Vec<u8>, u8),
+    /// Corrupted, incomplete or too long.
+    Bad,
+}
+
+/// Reads a data subpacket and checks its CRC.
+fn read_subpacket(link: &mut Link, crc32_used: bool) -> Result<Subpacket, TransferError> {
+    let mut data = Vec::with_capacity(SUBPACKET_SIZE);
+    let end = loop {
+        match read_zdle(link)? {
+            Zdle::Byte(b) => data.push(b),
+            Zdle::End(e) => break e,
+            Zdle::Bad | Zdle::Timeout => return Ok(Subpacket::Bad),
+        }
+        if data.len() > MAX_SUBPACKET { return Ok(Subpacket::Bad) }
+    };
+    let crc = match read_zdle_bytes(link, if crc32_used { 4 } else { 2 })? {
+        Some(v) => v,
+        None => return Ok(Subpacket::Bad)
+    };
+    data.push(end);
+    let valid = if crc32_used {
+        crc32(&data).to_le_bytes()[..] == crc[..]
+    } else {
+        crc16(&data).to_be_bytes()[..] == crc[..]
+    };
+    data.pop();
+    Ok(if valid { Subpacket::Data(data, end) } else { Subpacket::Bad })
+}
+
+/// Sends a batch of files.
+///
+/// * `flow` - The flow control on the port, without any the amount of data in flight is limited
+///   to a window acknowledged by the receiver.
+/// * `resume` - Ask the receiver to resume any partially received files.
+pub fn send(link: &mut Link, paths: &[String], flow: FlowControl, resume: bool) -> Result<u64, TransferError> {
+    link.write_all(RZ_COMMAND)?;
+    let rinit = wait_for_rinit(link)?;
+    let flags = rinit.zf0();
+    let rxbuflen = u16::from_le_bytes([rinit.data[0], rinit.data[1]]) as u64;
+    let mut enc = Encoder::new(flags & CANFC32 != 0, flags & ESCCTL != 0);
+
+    // A receiver that can't overlap disk and serial I/O, or has a limited buffer, waits for
+    // each segment to be acknowledged, otherwise the window only applies without flow control
+    let segment = if flags & (CANFDX | CANOVIO) != CANFDX | CANOVIO {
+        if rxbuflen > 0 { rxbuflen } else { SUBPACKET_SIZE as u64 }
+    } else {
+        rxbuflen
+    };
+    let window = if flow == FlowControl::None { WINDOW } else { 0 };
+
+    let mut sent = 0u64;
+    for path in paths {
+        sent += send_file(link, Path::new(path), &mut enc, resume, segment, window)?;
+        println!("\r");
+    }
+
+    for _ in 0..MAX_RETRIES {
+        link.write_all(&hex_header(&Header::pos(ZFIN, 0)))?;
+        if let Some((Header { kind: ZFIN, .. }, _)) = read_header(link, HEADER_TIMEOUT)? {
+            link.write_all(b"OO")?;
+            return Ok(sent);
+        }
+    }
+    Err(TransferError::TooManyRetries)
+}
+
+/// Sends ZRQINIT until the receiver responds with its ZRINIT.
+fn wait_for_rinit(link: &mut Link) -> Result<Header, TransferError> {
+    for _ in 0..MAX_RETRIES {
+        link.write_all(&hex_header(&Header::pos(ZRQINIT, 0)))?;
+        loop {
+            match read_header(link, HEADER_TIMEOUT)? {
+                Some((h, _)) if h.kind == ZRINIT => return Ok(h),
+                Some((h, _)) if h.kind == ZCHALLENGE => link.write_all(&hex_header(&Header { kind: ZACK, data: h.data }))?,
+                Some((h, _)) if h.kind == ZABORT || h.kind == ZCAN => return Err(TransferError::RemoteCancelled),
+                Some(_) => continue,
+                None => break,
+            }
+        }
+    }
+    Err(TransferError::TimedOut)
+}
+
+/// Offers a single file to the receiver and sends it from the position asked for.
+fn send_file(link: &mut Link,
+    path: &Path,
+    enc: &mut Encoder,
+    resume: bool,
+    segment: u64,
+    window: u64) -> Result<u64, TransferError>
+{
+    let mut file = File::open(path).map_err(TransferError::File)?;
+    let info = FileInfo::from_file(path, &file)?;
+    let mut progress = Progress::new(&format!("zmodem {}", info.name), info.size);
+    let zfile = Header::flags(ZFILE, if resume { ZCRESUM } else { ZCBIN });
+
+    let mut retries = 0;
+    let start = 'offer: loop {
+        let mut frame = enc.bin_header(&zfile);
+        frame.extend(enc.subpacket(&info.to_bytes(), ZCRCW));
+        link.write_all(&frame)?;
+        loop {
+            match read_header(link, HEADER_TIMEOUT)? {
+                Some((h, _)) if h.kind == ZRPOS => break 'offer h.position(),
+                Some((h, _)) if h.kind == ZSKIP => {
+                    put_skipped(&info.name);
+                    return Ok(0);
+                },
+                Some((h, _)) if h.kind == ZCRC => {
+                    // The receiver checks a partial file before asking for a position, so wait
+                    // for its next header rather than offering the file again
+                    retries += 1;
+                    if retries >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+                    let crc = file_crc(&mut file, h.position())?;
+                    link.write_all(&enc.bin_header(&Header { kind: ZCRC, data: crc.to_le_bytes() }))?;
+                },
+                Some((h, _)) if h.kind == ZABORT || h.kind == ZCAN || h.kind == ZFERR =>
+                    return Err(TransferError::RemoteCancelled),
+                _ => break,
+            }
+        }
+        retries += 1;
+        if retries >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+    };
+
+    let mut pos = start;
+    progress.add_bytes(pos);
+    let mut retries = 0;
+    let mut buf = vec![0u8; SUBPACKET_SIZE];
+    'data: loop {
+        if retries >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+        file.seek(SeekFrom::Start(pos)).map_err(TransferError::File)?;
+        progress.set_bytes(pos);
+        link.write_all(&enc.bin_header(&Header::pos(ZDATA, pos)))?;
+        let mut acked = pos;
+        let mut since_sync = 0u64;
+
+        loop {
+            let n = xmodem::read_full(&mut file, &mut buf)?;
+            let eof = n < buf.len();
+            since_sync += n as u64;
+            let end = if eof {
+                ZCRCE
+            } else if segment > 0 && since_sync + SUBPACKET_SIZE as u64 > segment {
+                ZCRCW
+            } else if window > 0 && pos + n as u64 - acked >= window / 2 {
+                ZCRCQ
+            } else {
+                ZCRCG
+            };
+            link.write_all(&enc.subpacket(&buf[..n], end))?;
+            pos += n as u64;
+            progress.add_bytes(n as u64);
+
+            if end == ZCRCW {
+                since_sync = 0;
+                match wait_for_ack(link, pos)? {
+                    Response::Ack => { acked = pos; retries = 0 },
+                    Response::Rpos(p) => {
+                        if p > acked { retries = 0 }
+                        pos = p;
+                        retries += 1;
+                        progress.add_retry();
+                        continue 'data
+                    },
+                    Response::Timeout => { pos = acked; retries += 1; progress.add_retry(); continue 'data },
+                }
+            }
+            while link.pending() {
+                match read_header(link, Duration::ZERO)? {
+                    Some((h, _)) if h.kind == ZRPOS => {
+                        if h.position() > acked { retries = 0 }
+                        pos = h.position();
+                        retries += 1;
+                        progress.add_retry();
+                        continue 'data;
+                    },
+                    Some((h, _)) if h.kind == ZACK => {
+                        if h.position() > acked { retries = 0 }
+                        acked = h.position();
+                    },
+                    Some((h, _)) if h.kind == ZABORT || h.kind == ZCAN || h.kind == ZFERR =>
+                        return Err(TransferError::RemoteCancelled),
+                    _ => (),
+                }
+            }
+            if window > 0 && pos - acked >= window {
+                match wait_for_ack(link, pos)? {
+                    Response::Ack => { acked = pos; retries = 0 },
+                    Response::Rpos(p) => {
+                        if p > acked { retries = 0 }
+                        pos = p;
+                        retries += 1;
+                        progress.add_retry();
+                        continue 'data
+                    },
+                    Response::Timeout => { pos = acked; retries += 1; progress.add_retry(); continue 'data },
+                }
+            }
+            if eof { break }
+        }
+
+        loop {
+            link.write_all(&enc.bin_header(&Header::pos(ZEOF, pos)))?;
+            match read_header(link, HEADER_TIMEOUT)? {
+                Some((h, _)) if h.kind == ZRINIT => return Ok(pos - start),
+                Some((h, _)) if h.kind == ZRPOS => {
+                    if h.position() > acked { retries = 0 }
+                    pos = h.position();
+                    retries += 1;
+                    progress.add_retry();
+                    continue 'data;
+                },
+                Some((h, _)) if h.kind == ZSKIP => {
+                    put_skipped(&info.name);
+                    return Ok(pos - start);
+                },
+                Some((h, _)) if h.kind == ZABORT || h.kind == ZCAN || h.kind == ZFERR =>
+                    return Err(TransferError::RemoteCancelled),
+                _ => {
+                    retries += 1;
+                    if retries >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+                },
+            }
+        }
+    }
+}
+
+/// The receiver's response to a ZCRCQ or ZCRCW subpacket.
+enum Response {
+    /// All the data sent has been acknowledged.
+    Ack,
+    /// The receiver wants the data resent from a position.
+    Rpos(u64),
+    /// The receiver didn't respond.
+    Timeout,
+}
+
+/// Waits for the receiver to acknowledge everything up to `pos`.
+fn wait_for_ack(link: &mut Link, pos: u64) -> Result<Response, TransferError> {
+    loop {
+        match read_header(link, HEADER_TIMEOUT)? {
+            Some((h, _)) if h.kind == ZACK && h.position() == pos => return Ok(Response::Ack),
+            Some((h, _)) if h.kind == ZRPOS => return Ok(Response::Rpos(h.position())),
+            Some((h, _)) if h.kind == ZABORT || h.kind == ZCAN || h.kind == ZFERR =>
+                return Err(TransferError::RemoteCancelled),
+            Some(_) => continue,
+            None => return Ok(Response::Timeout),
+        }
+    }
+}
+
+/// Calculates the CRC-32 of the first `len` bytes of the file (or all of it if `len` is 0), for
+/// the receiver to check a partial file before resuming.
+fn file_crc(file: &mut File, len: u64) -> Result<u32, TransferError> {
+    file.seek(SeekFrom::Start(0)).map_err(TransferError::File)?;
+    let mut data = vec![];
+    if len == 0 {
+        file.read_to_end(&mut data).map_err(TransferError::File)?;
+    } else {
+        file.take(len).read_to_end(&mut data).map_err(TransferError::File)?;
+    }
+    Ok(crc32(&data))
+}
+
+/// Receives a batch of files in to `dir` until the sender finishes the session.
+///
+/// * `resume` - Resume any partially received files, even if the sender didn't ask to.
+pub fn receive(link: &mut Link, dir: &Path, resume: bool) -> Result<u64, TransferError> {
+    let rinit = hex_header(&Header::flags(ZRINIT, CANFDX | CANOVIO | CANFC32));
+    let mut received = 0u64;
+    let mut retries = 0;
+    let mut send_rinit = true;
+    loop {
+        if send_rinit { link.write_all(&rinit)? }
+        send_rinit = true;
+        let (header, crc32_used) = match read_header(link, HEADER_TIMEOUT)? {
+            Some(v) => v,
+            None => {
+                retries += 1;
+                if retries >= MAX_RETRIES { return Err(TransferError::TimedOut) }
+                continue;
+            }
+        };
+        match header.kind {
+            ZSINIT => {
+                // The attention string is only used for breaking in to senders without full
+                // duplex, nothing to do with it
+                if let Subpacket::Data(..) = read_subpacket(link, crc32_used)? {
+                    link.write_all(&hex_header(&Header::pos(ZACK, 0)))?;
+                    send_rinit = false;
+                }
+            },
+            ZFILE => {
+                let info = match read_subpacket(link, crc32_used)? {
+                    Subpacket::Data(data, _) => FileInfo::from_bytes(&data)?,
+                    Subpacket::Bad => {
+                        link.write_all(&hex_header(&Header::pos(ZNAK, 0)))?;
+                        send_rinit = false;
+                        continue;
+                    }
+                };
+                let info = match info {
+                    Some(v) => v,
+                    None => return Err(TransferError::Protocol("empty file name")),
+                };
+                let resume = resume || header.zf0() == ZCRESUM;
+                received += receive_file(link, dir, &info, resume)?;
+                retries = 0;
+                println!("\r");
+            },
+            ZFIN => {
+                link.write_all(&hex_header(&Header::pos(ZFIN, 0)))?;
+                let mut oo = [0u8; 2];
+                let _ = link.read_exact(&mut oo, Duration::from_secs(1))?;
+                return Ok(received);
+            },
+            ZCOMMAND => return Err(TransferError::Unsupported("remote commands are not supported")),
+            ZABORT | ZCAN => return Err(TransferError::RemoteCancelled),
+            _ => (),
+        }
+    }
+}
+
+/// Receives the data for a single file after its ZFILE, returning the number of bytes received.
+fn receive_file(link: &mut Link, dir: &Path, info: &FileInfo, resume: bool) -> Result<u64, TransferError> {
+    let path = dir.join(&info.name);
+    let existing = std::fs::metadata(&path).map(|m| m.len()).ok();
+    let (mut file, start) = match (existing, info.size) {
+        (Some(len), Some(size)) if resume && len == size => {
+            link.write_all(&hex_header(&Header::pos(ZSKIP, 0)))?;
+            println!("\r\n*** zmodem: {} already received, skipped\r", info.name);
+            return Ok(0);
+        },
+        (Some(len), Some(size)) if resume && len < size => {
+            let file = OpenOptions::new().append(true).open(&path).map_err(TransferError::File)?;
+            (file, len)
+        },
+        _ => (File::create(&path).map_err(TransferError::File)?, 0)
+    };
+
+    let mut progress = Progress::new(&format!("zmodem {}", info.name), info.size);
+    progress.add_bytes(start);
+    let mut pos = start;
+    let mut retries = 0;
+    link.write_all(&hex_header(&Header::pos(ZRPOS, pos)))?;
+
+    loop {
+        if retries >= MAX_RETRIES { return Err(TransferError::TooManyRetries) }
+        let (header, crc32_used) = match read_header(link, HEADER_TIMEOUT)? {
+            Some(v) => v,
+            None => {
+                retries += 1;
+                progress.add_retry();
+                link.write_all(&hex_header(&Header::pos(ZRPOS, pos)))?;
+                continue;
+            }
+        };
+        match header.kind {
+            ZDATA if header.position() == pos => (),
+            ZDATA => {
+                retries += 1;
+                progress.add_retry();
+                link.purge(Duration::from_millis(100))?;
+                link.write_all(&hex_header(&Header::pos(ZRPOS, pos)))?;
+                continue;
+            },
+            ZEOF if header.position() == pos => {
+                if let Some(mtime) = info.mtime {
+                    let _ = file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+                }
+                return Ok(pos - start);
+            },
+            ZFILE => {
+                // The sender didn't get our ZRPOS
+                let _ = read_subpacket(link, crc32_used)?;
+                link.write_all(&hex_header(&Header::pos(ZRPOS, pos)))?;
+                continue;
+            },
+            ZNAK => {
+                link.write_all(&hex_header(&Header::pos(ZRPOS, pos)))?;
+                continue;
+            },
+            ZABORT | ZCAN | ZFIN => return Err(TransferError::RemoteCancelled),
+            _ => continue,
+        }
+
+        loop {
+            match read_subpacket(link, crc32_used)? {
+                Subpacket::Data(data, end) => {
+                    file.write_all(&data).map_err(TransferError::File)?;
+                    pos += data.len() as u64;
+                    retries = 0;
+                    progress.add_bytes(data.len() as u64);
+                    match end {
+                        ZCRCG => (),
+                        ZCRCQ => link.write_all(&hex_header(&Header::pos(ZACK, pos)))?,
+                        ZCRCW => {
+                            link.write_all(&hex_header(&Header::pos(ZACK, pos)))?;
+                            break;
+                        },
+                        _ => break,
+                    }
+                },
+                Subpacket::Bad => {
+                    retries += 1;
+                    progress.add_retry();
+                    link.purge(Duration::from_millis(100))?;
+                    link.write_all(&hex_header(&Header::pos(ZRPOS, pos)))?;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Shows that the receiver skipped a file.
+fn put_skipped(name: &str) {
+    println!("\r\n*** zmodem: {} skipped by remote\r", name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::testlink::{self, run, temp_dir};
+
+    /// Some data with every byte value in it, so all the escapes get used.
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|v| (v * 31 + v / 256) as u8).collect()
+    }
+
+    /// Writes a file to send in to a new directory, returning its path.
+    fn file_to_send(test: &str, data: &[u8]) -> String {
+        let path = temp_dir(test).join("send.bin");
+        std::fs::write(&path, data).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// Reads headers until one of the kind given arrives, panicking if nothing does.
+    fn expect_header(link: &mut Link, kind: u8) -> (Header, bool) {
+        loop {
+            match read_header(link, Duration::from_secs(5)).unwrap() {
+                Some(v) if v.0.kind == kind => return v,
+                Some(_) => continue,
+                None => panic!("no header of type {} arrived", kind),
+            }
+        }
+    }
+
+    /// Plays the receiver up to the ZFILE, returning the file info offered.
+    fn start_session(link: &mut Link) -> FileInfo {
+        expect_header(link, ZRQINIT);
+        link.write_all(&hex_header(&Header::flags(ZRINIT, CANFDX | CANOVIO | CANFC32))).unwrap();
+        let (_, crc32_used) = expect_header(link, ZFILE);
+        match read_subpacket(link, crc32_used).unwrap() {
+            Subpacket::Data(data, ZCRCW) => FileInfo::from_bytes(&data).unwrap().unwrap(),
+            _ => panic!("bad ZFILE subpacket"),
+        }
+    }
+
+    /// A receiver checking a partial file with ZCRC should get the CRC back and nothing else,
+    /// then the data from the position it asks for.
+    #[test]
+    fn crc_check_before_resume() {
+        let data = test_data(5000);
+        let path = file_to_send("zcrc", &data);
+        let expected = data.clone();
+        let (sent, received) = run(
+            move |link| send(link, &[path], FlowControl::Hardware, true),
+            move |link| {
+                start_session(link);
+                link.write_all(&hex_header(&Header::pos(ZCRC, 1000))).unwrap();
+                let (crc, _) = expect_header(link, ZCRC);
+                assert_eq!(u32::from_le_bytes(crc.data), crc32(&expected[..1000]));
+
+                link.write_all(&hex_header(&Header::pos(ZRPOS, 1000))).unwrap();
+                let (zdata, crc32_used) = read_header(link, Duration::from_secs(5)).unwrap().unwrap();
+                assert_eq!((zdata.kind, zdata.position()), (ZDATA, 1000), "the file was offered again");
+                let mut received = vec![];
+                loop {
+                    match read_subpacket(link, crc32_used).unwrap() {
+                        Subpacket::Data(v, end) => {
+                            received.extend(v);
+                            if end == ZCRCE { break }
+                        },
+                        Subpacket::Bad => panic!("bad data subpacket"),
+                    }
+                }
+                expect_header(link, ZEOF);
+                link.write_all(&hex_header(&Header::flags(ZRINIT, CANFDX | CANOVIO | CANFC32))).unwrap();
+                expect_header(link, ZFIN);
+                link.write_all(&hex_header(&Header::pos(ZFIN, 0))).unwrap();
+                received
+            },
+        );
+        assert_eq!(sent.unwrap(), 4000);
+        assert_eq!(received, data[1000..]);
+    }
+
+    /// A receiver that keeps asking for CRCs is given up on rather than answered forever.
+    #[test]
+    fn endless_crc_requests_give_up() {
+        let path = file_to_send("zcrc-endless", &test_data(100));
+        let (sent, replies) = run(
+            move |link| send(link, &[path], FlowControl::Hardware, true),
+            |link| {
+                start_session(link);
+                let mut replies = 0;
+                for _ in 0..MAX_RETRIES * 3 {
+                    link.write_all(&hex_header(&Header::pos(ZCRC, 50))).unwrap();
+                    match read_header(link, Duration::from_secs(2)) {
+                        Ok(Some((h, _))) if h.kind == ZCRC => replies += 1,
+                        _ => break,
+                    }
+                }
+                replies
+            },
+        );
+        assert!(matches!(sent, Err(TransferError::TooManyRetries)));
+        assert!(replies < MAX_RETRIES);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    /// Feeds bytes in to a link as if the remote had sent them, and reads them back with `f`.
+    fn read_back<R>(data: &[u8], f: impl FnOnce(&mut Link) -> R) -> R {
+        let (a, b) = testlink::pipe();
+        a.send(data);
+        testlink::with_link(b, f)
+    }
+
+    #[test]
+    fn escaping() {
+        let mut enc = Encoder::new(false, false);
+        let mut out = vec![];
+        enc.extend(&mut out, &[ZDLE, XON, XOFF, 0x10, 0x90, 0x91, 0x93, b'a', 0x01, 0x7F, 0xFF]);
+        assert_eq!(out, [ZDLE, ZDLE ^ 0x40, ZDLE, XON ^ 0x40, ZDLE, XOFF ^ 0x40, ZDLE, 0x50, ZDLE, 0xD0,
+            ZDLE, 0xD1, ZDLE, 0xD3, b'a', 0x01, 0x7F, 0xFF]);
+
+        // A CR is only escaped after an @
+        let mut out = vec![];
+        enc.extend(&mut out, b"\r@\r");
+        assert_eq!(out, [b'\r', b'@', ZDLE, b'\r' ^ 0x40]);
+
+        let mut enc = Encoder::new(false, true);
+        let mut out = vec![];
+        enc.extend(&mut out, &[0x01, 0x81, b' ']);
+        assert_eq!(out, [ZDLE, 0x41, ZDLE, 0xC1, b' ']);
+    }
+
+    /// Every byte value comes back out of the decoder as it went in to the encoder.
+    #[test]
+    fn escaping_round_trip() {
+        for escape_ctl in [false, true] {
+            let data: Vec<u8> = (0..=255).chain(b"@\r\x18\x18".iter().copied()).collect();
+            let mut out = vec![];
+            Encoder::new(false, escape_ctl).extend(&mut out, &data);
+            let decoded = read_back(&out, |link| read_zdle_bytes(link, data.len())).unwrap();
+            assert_eq!(decoded, Some(data));
+        }
+    }
+
+    #[test]
+    fn escaped_rubouts() {
+        let decoded = read_back(&[ZDLE, ZRUB0, ZDLE, ZRUB1], |link| read_zdle_bytes(link, 2)).unwrap();
+        assert_eq!(decoded, Some(vec![0x7F, 0xFF]));
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let headers = [Header::pos(ZRPOS, 0x12345678), Header::flags(ZRINIT, CANFC32 | ESCCTL), Header::pos(ZDATA, 0x11)];
+        for header in headers {
+            let hex = read_back(&hex_header(&header), |link| read_header(link, BYTE_TIMEOUT)).unwrap();
+            assert_eq!(hex, Some((header, false)));
+            for crc32_used in [false, true] {
+                let bin = Encoder::new(crc32_used, true).bin_header(&header);
+                let read = read_back(&bin, |link| read_header(link, BYTE_TIMEOUT)).unwrap();
+                assert_eq!(read, Some((header, crc32_used)));
+            }
+        }
+    }
+
+    /// Noise ahead of a header is skipped, a corrupted header isn't read.
+    #[test]
+    fn headers_after_noise_and_corrupted() {
+        let header = Header::pos(ZACK, 1234);
+        let mut data = b"garbage**\x18".to_vec();
+        data.extend(Encoder::new(true, false).bin_header(&header));
+        let read = read_back(&data, |link| read_header(link, BYTE_TIMEOUT)).unwrap();
+        assert_eq!(read, Some((header, true)));
+
+        let mut bin = Encoder::new(true, false).bin_header(&header);
+        bin[4] ^= 0x01;
+        let read = read_back(&bin, |link| read_header(link, Duration::from_millis(200))).unwrap();
+        assert_eq!(read, None);
+    }
+
+    #[test]
+    fn subpackets_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        for crc32_used in [false, true] {
+            for end in [ZCRCE, ZCRCG, ZCRCQ, ZCRCW] {
+                let encoded = Encoder::new(crc32_used, false).subpacket(&data, end);
+                match read_back(&encoded, |link| read_subpacket(link, crc32_used)).unwrap() {
+                    Subpacket::Data(v, e) => assert_eq!((v, e), (data.clone(), end)),
+                    Subpacket::Bad => panic!("subpacket with end {} read as bad", end),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_subpacket() {
+        let mut encoded = Encoder::new(true, false).subpacket(&test_data(100), ZCRCG);
+        encoded[10] ^= 0x01;
+        assert!(matches!(read_back(&encoded, |link| read_subpacket(link, true)), Ok(Subpacket::Bad)));
+    }
+
+    /// Sends a batch of files with the sender and receiver against each other.
+    #[test]
+    fn batch_round_trip() {
+        let files = [test_data(5000), test_data(SUBPACKET_SIZE * 3), vec![]];
+        let dir = temp_dir("zbatch");
+        let paths: Vec<String> = files.iter().enumerate().map(|(i, data)| {
+            let path = dir.join(format!("{}.bin", i));
+            std::fs::write(&path, data).unwrap();
+            path.to_string_lossy().to_string()
+        }).collect();
+        let rx = temp_dir("zbatch-rx");
+        let rx_dir = rx.clone();
+        let (sent, received) = run(
+            move |link| send(link, &paths, FlowControl::None, false),
+            move |link| receive(link, &rx_dir, false),
+        );
+        let total = files.iter().map(|v| v.len() as u64).sum::<u64>();
+        assert_eq!(sent.unwrap(), total);
+        assert_eq!(received.unwrap(), total);
+        for (i, data) in files.iter().enumerate() {
+            assert_eq!(&std::fs::read(rx.join(format!("{}.bin", i))).unwrap(), data);
+        }
+    }
+
+    /// A partially received file is resumed from where it got to, with the ZRPOS the receiver
+    /// sends after the ZFILE.
+    #[test]
+    fn resume_round_trip() {
+        let data = test_data(10000);
+        let path = file_to_send("zresume", &data);
+        let rx = temp_dir("zresume-rx");
+        std::fs::write(rx.join("send.bin"), &data[..3000]).unwrap();
+        let rx_dir = rx.clone();
+        let (sent, received) = run(
+            move |link| send(link, &[path], FlowControl::Hardware, true),
+            move |link| receive(link, &rx_dir, false),
+        );
+        assert_eq!(sent.unwrap(), 7000);
+        assert_eq!(received.unwrap(), 7000);
+        assert_eq!(std::fs::read(rx.join("send.bin")).unwrap(), data);
+    }
+
+    /// A file already received in full is skipped without being touched.
+    #[test]
+    fn resume_complete_file() {
+        let data = test_data(2000);
+        let path = file_to_send("zcomplete", &data);
+        let rx = temp_dir("zcomplete-rx");
+        std::fs::write(rx.join("send.bin"), &data).unwrap();
+        let rx_dir = rx.clone();
+        let (sent, received) = run(
+            move |link| send(link, &[path], FlowControl::Hardware, true),
+            move |link| receive(link, &rx_dir, true),
+        );
+        assert_eq!(sent.unwrap(), 0);
+        assert_eq!(received.unwrap(), 0);
+        assert_eq!(std::fs::read(rx.join("send.bin")).unwrap(), data);
+    }
+}
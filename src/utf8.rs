@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+
+/// How long the start of a character may wait for the rest before it's shown as invalid.
+const CHAR_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// What the decoder gives back as bytes are fed in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    /// A complete character.
+    Char(char),
+    /// Bytes that aren't valid UTF-8.
+    Invalid(Vec<u8>),
+}
+
+/// Decodes UTF-8 a byte at a time, keeping the start of a character until the rest of it arrives,
+/// so characters split across reads come out whole.
+pub struct Utf8Decoder {
+    /// The bytes of the character so far.
+    pending: Vec<u8>,
+    /// How many bytes the character being decoded has in total.
+    needed: usize,
+    /// When the last byte of the character was received.
+    last: Instant,
+}
+
+impl Utf8Decoder {
+
+    /// Creates a decoder with nothing pending.
+    pub fn new() -> Utf8Decoder {
+        Utf8Decoder { pending: Vec::with_capacity(4), needed: 0, last: Instant::now() }
+    }
+
+    /// Feeds in a byte, giving what it completes: nothing while a character is still arriving,
+    /// the character once it has, or the bytes that turned out to be invalid. If a byte can't
+    /// continue the character so far, that character is given as invalid and the byte decoded on
+    /// its own.
+    pub fn push(&mut self, byte: u8) -> Vec<Decoded> {
+        let mut res = Vec::new();
+        self.last = Instant::now();
+        if self.needed > 0 {
+            if self.continues(byte) {
+                self.pending.push(byte);
+                if self.pending.len() == self.needed {
+                    res.push(self.finish());
+                }
+                return res;
+            }
+            res.push(Decoded::Invalid(self.take()));
+        }
+        let needed = match byte {
+            0x00..=0x7F => {
+                res.push(Decoded::Char(byte as char));
+                return res;
+            },
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => {
+                res.push(Decoded::Invalid(vec![byte]));
+                return res;
+            }
+        };
+        self.pending.push(byte);
+        self.needed = needed;
+        res
+    }
+
+    /// Gives up on a character that hasn't had a byte in too long, giving back its bytes as
+    /// invalid, `None` if there isn't one.
+    pub fn flush_stale(&mut self) -> Option<Vec<u8>> {
+        if self.needed == 0 || self.last.elapsed() < CHAR_TIMEOUT { return None }
+        Some(self.take())
+    }
+
+    /// How long until the character being decoded times out, `None` if there isn't one.
+    pub fn time_left(&self) -> Option<Duration> {
+        if self.needed == 0 { return None }
+        Some(CHAR_TIMEOUT.saturating_sub(self.last.elapsed()))
+    }
+
+    /// Checks if the byte can come next in the character so far, ruling out overlong encodings,
+    /// surrogates and code points past U+10FFFF as early as they can be spotted.
+    fn continues(&self, byte: u8) -> bool {
+        let (low, high) = match (self.pending.len(), self.pending[0]) {
+            (1, 0xE0) => (0xA0, 0xBF),
+            (1, 0xED) => (0x80, 0x9F),
+            (1, 0xF0) => (0x90, 0xBF),
+            (1, 0xF4) => (0x80, 0x8F),
+            _ => (0x80, 0xBF),
+        };
+        (low..=high).contains(&byte)
+    }
+
+    /// Gives the character that's been completed.
+    fn finish(&mut self) -> Decoded {
+        let bytes = self.take();
+        match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+            Some(c) => Decoded::Char(c),
+            None => Decoded::Invalid(bytes)
+        }
+    }
+
+    /// Takes the bytes of the character so far, leaving nothing pending.
+    fn take(&mut self) -> Vec<u8> {
+        self.needed = 0;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Self {
+        Utf8Decoder::new()
+    }
+}
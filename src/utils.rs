@@ -1,8 +1,8 @@
 use std::io::Write;
 
 
-/// Number of spaces in tabs 
-pub const TABS: usize = 8;
+/// Number of columns between tab stops by default 
+pub const TABS: u16 = 8;
 
 /// A list of standard bauds. 
 pub const BAUDS: [u32; 30] = [
@@ -56,6 +56,13 @@ pub fn put_string(c: String) {
     let _ = std::io::stdout().flush();
 }
 
+/// Puts bytes on the terminal as they are, without any conversion, and flushes the buffer. 
+pub fn put_bytes(c: &[u8]) {
+    let mut out = std::io::stdout();
+    let _ = out.write_all(c);
+    let _ = out.flush();
+}
+
 /// Erases the last character printed to the terminal, flushes the buffer. 
 pub fn del_char() {
     print!("\x08 \x08");